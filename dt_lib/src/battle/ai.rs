@@ -0,0 +1,149 @@
+use crate::{
+    battle::{
+        army::{Army, MAX_LINES, MAX_TROOPS},
+        battlefield::{
+            field_type, handle_action, move_thing, troop_inactive, Action, BattleAwards,
+            BattleInfo, Field,
+        },
+        control::Control,
+        preview::{preview_attack, AttackPreview},
+        troop::Troop,
+    },
+    units::unit::{calclate_unit_power, ActionResult, Unit},
+};
+
+const KILL_BONUS: f32 = 1.5;
+const SUPPORT_MODIFIER: f32 = 0.5;
+const BLESS_VALUE: f32 = 0.1;
+
+fn score_target(target: &Unit, preview: &AttackPreview) -> f32 {
+    let power = calclate_unit_power(target);
+    let max_hp = target.modified.max_hp.max(1) as f32;
+    let hp = target.modified.hp.max(0) as f32;
+    let mut score = if preview.hit > 0 {
+        let kill = if preview.kills { power * KILL_BONUS } else { 0. };
+        power * (preview.hit as f32).min(hp) / max_hp + kill
+    } else {
+        let bless = if !preview.effects.is_empty() {
+            BLESS_VALUE
+        } else {
+            0.
+        };
+        power * (preview.heal as f32 / max_hp + bless) * SUPPORT_MODIFIER
+    };
+    if preview.attacker_dies {
+        score -= power * KILL_BONUS;
+    }
+    score
+}
+
+/// Value of the hits the other troops reached by the pattern of the action would lose
+fn score_others(army: &Army, preview: &AttackPreview) -> f32 {
+    preview
+        .others
+        .iter()
+        .map(|(index, hit)| {
+            let troop = army.troops[*index].get();
+            let unit = &troop.unit;
+            let hp = unit.modified.hp.max(0) as f32;
+            calclate_unit_power(unit) * (*hit as f32).min(hp) / unit.modified.max_hp.max(1) as f32
+        })
+        .sum()
+}
+
+/// Free cell of the front line a melee troop standing in the back can step into.
+fn advance_cell(army: &Army, troop: &Troop) -> Option<usize> {
+    let size = troop.unit.info.size;
+    if troop.unit.modified.damage.hand == 0
+        || size.1 > 1
+        || field_type(troop.pos.into(), *MAX_TROOPS) != Field::Back
+    {
+        return None;
+    }
+    let columns = *MAX_TROOPS / MAX_LINES;
+    (0..=(columns - size.0))
+        .find(|&column| Army::fit_to(&army.hitmap, size, columns, MAX_LINES, 1, column))
+        .map(|column| columns + column)
+}
+
+/// Picks an action for the currently active unit: the most valuable attack or support from `can_interact`,
+/// otherwise stepping into the front line or skipping the move.
+pub fn choose_action(battle: &BattleInfo, armys: &Vec<Army>) -> Option<Action> {
+    let (active_army, active_index) = battle.active_unit?;
+    let active = armys[active_army].troops.get(active_index)?.get().clone();
+    if troop_inactive(&active) {
+        return None;
+    }
+    let mut best: Option<(f32, Action)> = None;
+    for &(army, pos) in battle.can_interact.iter().flatten() {
+        let (Some(index), Some(side)) = (armys[army].hitmap[pos], battle.side(army)) else {
+            continue;
+        };
+        let Some(preview) =
+            preview_attack(battle, armys, (active_army, active_index), (army, index))
+        else {
+            continue;
+        };
+        let score = score_target(&armys[army].troops[index].get().unit, &preview)
+            + score_others(&armys[army], &preview);
+        if score > 0. && best.as_ref().map_or(true, |(best_score, _)| score > *best_score) {
+            best = Some((score, Action::Cell(pos, side)));
+        }
+    }
+    if let Some((_, action)) = best {
+        return Some(action);
+    }
+    if let Some(to) = advance_cell(&armys[active_army], &active) {
+        return Some(Action::Move(active_army, active_index, to));
+    }
+    Some(Action::Defend)
+}
+
+/// Plays every turn that belongs to a `Control::PC` army until a player has to act or the battle ends.
+pub fn play_pc_turns(
+    battle: &mut BattleInfo,
+    armys: &mut Vec<Army>,
+) -> Vec<(ActionResult, (usize, usize))> {
+    play_turns(battle, armys, |army| matches!(army.control, Control::PC))
+}
+/// Plays the whole battle with the AI on both sides and hands out the results the same way
+/// a played battle does, e.g. for a battle of two PC armies or a fight the player skipped.
+pub fn auto_resolve(battle: &mut BattleInfo, armys: &mut Vec<Army>) -> BattleAwards {
+    while !battle.is_over() {
+        play_turns(battle, armys, |_| true);
+        move_thing(battle, armys);
+    }
+    battle.end(armys)
+}
+fn play_turns(
+    battle: &mut BattleInfo,
+    armys: &mut Vec<Army>,
+    played: impl Fn(&Army) -> bool,
+) -> Vec<(ActionResult, (usize, usize))> {
+    let mut results = Vec::new();
+    while !battle.is_over() {
+        let Some(active) = battle.active_unit else {
+            break;
+        };
+        if !played(&armys[active.0]) {
+            break;
+        }
+        let moves = armys[active.0].troops[active.1].get().unit.modified.moves;
+        let Some(action) = choose_action(battle, armys) else {
+            break;
+        };
+        if let Some(res) = handle_action(action, battle, armys).0 {
+            results.push(res);
+        }
+        let stalled = battle.active_unit == Some(active)
+            && armys[active.0]
+                .troops
+                .get(active.1)
+                .is_some_and(|troop| troop.get().unit.modified.moves == moves);
+        if stalled {
+            // The rules refused the chosen action, spend the move instead of looping forever
+            handle_action(Action::Defend, battle, armys);
+        }
+    }
+    results
+}
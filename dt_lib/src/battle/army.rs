@@ -11,6 +11,7 @@ use crate::{
     },
     mutrc::SendMut,
     parse::SETTINGS,
+    spells::get_spell,
     units::unit::UnitPos,
 };
 use advini::{Ini, IniParseError, Section, SectionError, Sections};
@@ -19,7 +20,7 @@ use num::{integer::sqrt, pow};
 use once_cell::sync::Lazy;
 use pathfinding::directed::astar::astar;
 
-use super::control::Control;
+use super::control::{Control, Fraction};
 #[derive(Clone, Debug, Default, Sections)]
 #[alkahest(Deserialize, Serialize, SerializeRef, Formula)]
 pub struct ArmyStats {
@@ -70,8 +71,11 @@ pub struct Army {
     pub defeated: bool,
     //#[default_value = "Control::PC"]
     pub control: Control,
+    pub fraction: Fraction,
     //#[unused]
     pub path: Vec<(usize, usize)>,
+    /// Indexes in `SPELLS` of the spells the army can cast
+    pub spells: Vec<usize>,
 }
 // [TODO REMOVE NAHUJ]
 // impl<'de> Deserialize<'de, Self> for Army {
@@ -106,11 +110,16 @@ impl Army {
             hitmap,
             defeated: false,
             stats,
+            fraction: match control {
+                Control::Player(_) => Fraction::Player,
+                Control::PC => Fraction::default(),
+            },
             control,
             inventory,
             pos,
             active,
             path: Vec::new(),
+            spells: Vec::new(),
         };
         for troop in troops {
             army.add_troop(troop).ok();
@@ -233,12 +242,62 @@ impl Army {
             self.inventory.remove(index);
         }
     }
+    /// Uses a potion from the inventory on the troop with the index, the potion is used up
+    pub fn use_potion(&mut self, item: usize, troop: usize) -> bool {
+        let Some(potion) = self
+            .inventory
+            .iter()
+            .find(|potion| potion.index == item)
+            .copied()
+        else {
+            return false;
+        };
+        let Some(troop) = self.troops.get(troop) else {
+            return false;
+        };
+        if !potion.drink(&mut troop.get().unit) {
+            return false;
+        }
+        self.remove_item(item);
+        true
+    }
+    /// Casts a spell of the spellbook on the troops, mana is spent if the spell changed any of them
+    pub fn cast_spell(&mut self, spell: usize, troops: &[TroopType]) -> bool {
+        if !self.spells.contains(&spell) {
+            return false;
+        }
+        let Some(info) = get_spell(spell) else {
+            return false;
+        };
+        if self.stats.mana < info.cost {
+            return false;
+        }
+        let mut cast = false;
+        for troop in troops {
+            cast |= info.apply(&mut troop.get().unit);
+        }
+        if cast {
+            self.stats.mana -= info.cost;
+        }
+        cast
+    }
     pub fn get_troop(&self, pos: usize) -> Option<TroopType> {
         if let Some(index) = self.hitmap[pos] {
             return self.troops.get(index).cloned();
         };
         None
     }
+    /// Clone of the army that does not share troops with the original
+    pub fn deep_clone(&self) -> Self {
+        Army {
+            troops: self
+                .troops
+                .iter()
+                .map(|troop| SendMut::new(troop.get().clone()))
+                .collect(),
+            ..self.clone()
+        }
+    }
 }
 
 fn dist(p1: &(usize, usize), p2: &(usize, usize)) -> u32 {
@@ -318,3 +377,50 @@ pub fn find_path(
     )?;
     Some((path.0[1..].to_vec(), path.1))
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        battle::{
+            battlefield::{handle_action, Action},
+            events::BattleEvent,
+        },
+        parse::parse_items,
+        testing::gen_battle,
+        units::unit::ActionResult,
+    };
+
+    #[test]
+    fn potions() {
+        let _ = parse_items(Some("dt/Rus_Artefacts.ini"), &"Rus".into());
+        let (mut armys, mut battle) = gen_battle(0);
+        let active = battle.active_unit.unwrap();
+        {
+            let unit = &mut armys[active.0].troops[active.1].get().unit;
+            unit.stats.hp = 50;
+            unit.recalc();
+        }
+        assert_eq!(handle_action(Action::Potion(100, active.1), &mut battle, &mut armys).0, None);
+        armys[active.0].add_item(100);
+        let (res, events) = handle_action(Action::Potion(100, active.1), &mut battle, &mut armys);
+        assert_eq!(res, Some((ActionResult::Buff, active)));
+        assert!(events.contains(&BattleEvent::Heal(
+            (active.0, armys[active.0].troops[active.1].get().pos.into()),
+            25
+        )));
+        assert!(armys[active.0].inventory.is_empty());
+        let troop = armys[active.0].troops[active.1].get();
+        assert_eq!(troop.unit.modified.hp, 75);
+        assert_eq!(troop.battle_stats.used_moves, 1);
+        drop(troop);
+
+        // Elixirs change the stats for good
+        armys[1].add_item(95);
+        assert!(!armys[1].use_potion(95, 100));
+        assert!(armys[1].use_potion(95, 0));
+        assert!(!armys[1].use_potion(95, 0));
+        let unit = &armys[1].troops[0].get().unit;
+        assert_eq!(unit.stats.max_moves, 2);
+        assert_eq!(unit.stats.max_hp, 70);
+    }
+}
@@ -1,16 +1,25 @@
 use crate::{
     battle::{
-        army::{Army, TroopType, MAX_TROOPS},
-        troop::Troop,
+        army::{Army, TroopType, MAX_LINES, MAX_TROOPS},
+        events::{unit_events, BattleEvent, BattleSnapshot},
+        replay::BattleLog,
+        troop::{Troop, TroopBattleStats},
     },
+    effects::effect::{EffectInfo, ToEndEffect},
     items::item::Item,
-    map::map::GameMap,
+    map::{map::GameMap, terrain::BattleTerrain},
     network::net::*,
-    units::unit::*,
+    rng::GameRng,
+    spells::{get_spell, SpellTarget},
+    units::{
+        unit::*,
+        unitstats::{Modify, ModifyDefence, ModifyUnitStats},
+    },
 };
 use alkahest::{alkahest, serialize, serialized_size};
+use math_thingies::Percent;
 use renet::DefaultChannel;
-use std::cmp::Ordering::*;
+use std::{cmp::Ordering::*, str::FromStr};
 
 #[derive(Copy, Clone, Debug, PartialEq, PartialOrd)]
 pub enum Field {
@@ -28,6 +37,217 @@ pub fn field_type(index: usize, max_troops: usize) -> Field {
 pub fn troop_inactive(troop: &Troop) -> bool {
     troop.unit.modified.moves < 1 || troop.unit.is_dead()
 }
+/// Remembers the speed the troop takes its place in the turn order with, false if it can't act
+fn queue_troop(armys: &Vec<Army>, (army, index): (usize, usize)) -> bool {
+    let mut troop = armys[army].troops[index].get();
+    troop.battle_stats.turn_speed = troop.unit.modified.speed;
+    !troop_inactive(&troop)
+}
+
+const EXP_CORRECTION: f64 = 100.;
+const MAIN_EXP_CORRECTION: f64 = 100.;
+
+/// Statistics of one side of a battle, the experience formula is based on them
+#[derive(Clone, Default, Debug)]
+#[alkahest(Deserialize, Serialize, SerializeRef, Formula)]
+pub struct ArmyBattleStats {
+    pub first_tactic_cost: u64,
+    pub tactic_cost: u64,
+    pub first_max_units: u64,
+    /// Hits of the army at the start of the battle
+    pub all_hit: u64,
+    /// Hits lost by the army
+    pub lost_hit: u64,
+    /// Hits the enemy lost from the army's attacks
+    pub normal_lost_hit: u64,
+    /// The strongest single hit of the army
+    pub turn_max_hit: u64,
+    pub old_experience: u64,
+    pub experience: u64,
+}
+impl ArmyBattleStats {
+    pub fn new(army: &Army) -> Self {
+        let tactic_cost = tactic_cost(army);
+        Self {
+            first_tactic_cost: tactic_cost,
+            tactic_cost,
+            first_max_units: army.troops.len() as u64,
+            all_hit: army
+                .troops
+                .iter()
+                .map(|troop| troop.get().unit.modified.hp.max(0) as u64)
+                .sum(),
+            ..Default::default()
+        }
+    }
+    fn calc_experience(&mut self, enemy: &ArmyBattleStats) {
+        self.old_experience = if self.first_tactic_cost > 0 {
+            let ratio = enemy.first_tactic_cost as f64 / self.first_tactic_cost as f64;
+            let ratio = if ratio >= 1. {
+                1. + (ratio - 1.) * EXP_CORRECTION / 100.
+            } else {
+                1. - (1. - ratio) * EXP_CORRECTION / 100.
+            }
+            .clamp(0.25, 4.);
+            let experience =
+                enemy.first_tactic_cost.saturating_sub(enemy.tactic_cost) as f64 * ratio;
+            (experience * MAIN_EXP_CORRECTION / 100.).round() as u64
+        } else {
+            0
+        };
+        let base = (enemy.first_tactic_cost / 20) as f64;
+        let normal_lost_hit = self.normal_lost_hit as f64;
+        let experience = if self.lost_hit > 0 {
+            if self.normal_lost_hit == 0 {
+                let ratio = if self.all_hit > 0 {
+                    (self.all_hit as f64 - self.lost_hit as f64) / self.all_hit as f64
+                } else {
+                    0.
+                };
+                base * ratio.max(0.)
+            } else {
+                let ratio = (normal_lost_hit / self.lost_hit as f64).clamp(0.8, 3.);
+                base + normal_lost_hit * ratio + self.turn_max_hit as f64
+            }
+        } else {
+            base + normal_lost_hit * 3. + self.turn_max_hit as f64
+        };
+        self.experience = experience.round() as u64;
+    }
+}
+fn tactic_cost(army: &Army) -> u64 {
+    army.troops
+        .iter()
+        .map(|troop| {
+            let troop = troop.get();
+            if troop.is_dead() {
+                0
+            } else {
+                troop.unit.info.cost_hire
+            }
+        })
+        .sum()
+}
+/// Share of the army experience earned by a troop, depends on its row and how much it acted
+fn troop_experience(experience: u64, first_max_units: u64, troop: &Troop) -> u64 {
+    let row = match field_type(troop.pos.into(), *MAX_TROOPS) {
+        Field::Front => 1.,
+        Field::Back => 2.,
+        Field::Reserve => 3.,
+    };
+    let mut share = 0.25 * experience as f64 / first_max_units as f64;
+    let stats = troop.battle_stats;
+    let moves = stats.all_moves + stats.free_moves;
+    if moves > 0 {
+        share = (4. - row) * share + row * share * stats.used_moves as f64 / moves as f64;
+    }
+    if share < 0.5 {
+        share = 1.;
+    }
+    share.round() as u64
+}
+
+/// Experience a troop got, `army` is the army the troop belongs to on the map
+#[derive(Clone, Debug)]
+pub struct ExpAward {
+    pub army: usize,
+    pub troop: TroopType,
+    pub xp: u64,
+}
+/// What was handed out when the battle ended
+#[derive(Clone, Default, Debug)]
+pub struct BattleAwards {
+    pub experience: Vec<ExpAward>,
+    pub items: Vec<Item>,
+    pub gold: u64,
+    /// Amount of troops taken to `BattleInfo::prisoners`
+    pub prisoners: usize,
+}
+/// What the winner does with a captured troop
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PrisonerChoice {
+    /// Winner gets the surrender value of the unit as mana
+    RansomMana,
+    /// Winner gets the hire cost of the unit as gold
+    RansomGold,
+    /// Prisoner joins the winner army if there is a place for it
+    Recruit,
+    /// Prisoner returns to its army, it is lost if there is no place
+    Release,
+}
+
+/// Who wins a battle that lasted `max_rounds` rounds, army1 is the defender and army2 is the attacker
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+#[alkahest(Deserialize, Serialize, SerializeRef, Formula)]
+pub enum TimeoutRule {
+    #[default]
+    AttackerLoses,
+    DefenderLoses,
+    Draw,
+    /// The side with more tactical cost of the living troops wins, equal cost is a draw
+    MorePower,
+}
+impl FromStr for TimeoutRule {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim() {
+            "AttackerLoses" => Ok(Self::AttackerLoses),
+            "DefenderLoses" => Ok(Self::DefenderLoses),
+            "Draw" => Ok(Self::Draw),
+            "MorePower" => Ok(Self::MorePower),
+            rule => Err(format!("Unknown timeout rule {rule}")),
+        }
+    }
+}
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[alkahest(Deserialize, Serialize, SerializeRef, Formula)]
+pub struct BattleRules {
+    pub max_rounds: u64,
+    pub timeout: TimeoutRule,
+}
+impl Default for BattleRules {
+    fn default() -> Self {
+        Self {
+            max_rounds: 25,
+            timeout: TimeoutRule::AttackerLoses,
+        }
+    }
+}
+
+/// Higher defense of a building doesn't make the defenders any stronger
+const MAX_SIEGE_DEFENSE: u64 = 300;
+/// Army1 defends inside a building
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[alkahest(Deserialize, Serialize, SerializeRef, Formula)]
+pub struct Siege {
+    pub building: usize,
+    /// Defense of the building, defenders get this percent of hand and ranged defence units
+    pub defense: u64,
+}
+impl Siege {
+    fn effect(&self) -> ToEndEffect {
+        let percent = Percent::new(self.defense.min(MAX_SIEGE_DEFENSE) as i16);
+        ToEndEffect {
+            info: EffectInfo { lifetime: i32::MAX },
+            modify: ModifyUnitStats {
+                defence: ModifyDefence {
+                    hand_units: *Modify::default().percent_add(percent),
+                    ranged_units: *Modify::default().percent_add(percent),
+                    ..Default::default()
+                },
+                ..Default::default()
+            },
+        }
+    }
+}
+
+/// Where a battle happens, the terrain and the building change the stats of the troops at the start
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+#[alkahest(Deserialize, Serialize, SerializeRef, Formula)]
+pub struct BattlePlace {
+    pub terrain: Option<BattleTerrain>,
+    pub siege: Option<Siege>,
+}
 
 #[derive(Clone, Default, Debug)]
 #[alkahest(Deserialize, Serialize, SerializeRef, Formula)]
@@ -39,96 +259,318 @@ pub struct BattleInfo {
     pub move_count: u64,
     pub can_interact: Option<Vec<(usize, usize)>>,
     pub winner: Option<usize>,
+    /// Battle ended without a winner
+    pub draw: bool,
+    pub rules: BattleRules,
     pub dead: Vec<TroopType>,
+    /// Statistics of army1 and army2
+    pub stats: Vec<ArmyBattleStats>,
+    pub rng: GameRng,
+    /// Kept by the server, `for_clients` leaves it out
+    pub log: Option<BattleLog>,
+    /// Army that gave up the battle
+    pub surrendered: Option<usize>,
+    /// Troops that still act in the current round in the order of their turns, the active one first
+    pub initiative: Vec<(usize, usize)>,
+    /// Surrendered troops of the defeated army waiting for the decision of the winner
+    pub prisoners: Vec<TroopType>,
+    pub place: BattlePlace,
+    /// Troops of the building garrison fighting in army1, `GameMap::finish_battle` takes them back
+    pub garrison: Vec<TroopType>,
+    /// Armies that joined the battle with their side: 0 helps army1 and 1 helps army2
+    pub allies: Vec<(usize, usize)>,
+    /// Troops the allies lent to army1 or army2 with the army they belong to
+    pub lent: Vec<(usize, TroopType)>,
+    /// Armies that cast a spell with the round they did it in
+    pub spell_casts: Vec<(usize, u64)>,
+    /// Results of the battle were already handed out by `end`
+    pub ended: bool,
+    /// Events reported while an action is applied, `handle_action` hands them out
+    pub events: Vec<BattleEvent>,
 }
 impl BattleInfo {
-    pub fn new(armys: &mut Vec<Army>, army1: usize, army2: usize) -> Self {
+    pub fn new(armys: &mut Vec<Army>, army1: usize, army2: usize, rng: GameRng) -> Self {
+        Self::with_rules(armys, army1, army2, rng, BattleRules::default())
+    }
+    pub fn with_rules(
+        armys: &mut Vec<Army>,
+        army1: usize,
+        army2: usize,
+        rng: GameRng,
+        rules: BattleRules,
+    ) -> Self {
+        Self::with_place(armys, army1, army2, rng, rules, BattlePlace::default())
+    }
+    pub fn with_place(
+        armys: &mut Vec<Army>,
+        army1: usize,
+        army2: usize,
+        rng: GameRng,
+        rules: BattleRules,
+        place: BattlePlace,
+    ) -> Self {
         let mut battle = BattleInfo {
             army1,
             army2,
-            battle_ter: armys[army2].building.unwrap_or(0),
+            battle_ter: armys[army1].building.unwrap_or(0),
             winner: None,
+            rules,
+            rng,
+            log: Some(BattleLog::new(armys, army1, army2, rng, rules, place)),
+            place,
             ..Default::default()
         };
         battle.start(armys);
         battle
     }
+    /// Copy of the battle sent to the clients, without the log
+    pub fn for_clients(&mut self) -> Self {
+        let log = self.log.take();
+        let battle = self.clone();
+        self.log = log;
+        battle
+    }
     pub fn start(&mut self, armys: &mut Vec<Army>) {
         let army1 = &mut armys[self.army1];
 
         army1.troops.iter_mut().for_each(|troop| {
-            let unit = &mut troop.get().unit;
-            let bonus = unit.get_bonus();
-            bonus.on_battle_start(unit, &self);
-            unit.bonus = bonus;
+            let mut troop = troop.get();
+            let unit = &mut troop.unit;
+            for bonus in unit.bonuses() {
+                bonus.on_battle_start(unit, &self);
+            }
+            unit.bonus = unit.get_bonus();
+            if let Some(terrain) = self.place.terrain {
+                for effect in terrain.effects(unit.info.unit_type) {
+                    unit.add_effect(effect);
+                }
+            }
+            if let Some(siege) = self.place.siege {
+                unit.add_effect(siege.effect());
+            }
             unit.recalc();
+            troop.battle_stats = TroopBattleStats {
+                all_moves: troop.unit.modified.moves.max(0) as u64,
+                ..Default::default()
+            };
         });
         let army2 = &mut armys[self.army2];
         army2.troops.iter_mut().for_each(|troop| {
-            let unit = &mut troop.get().unit;
-            let bonus = unit.get_bonus();
-            bonus.on_battle_start(unit, &self);
-            unit.bonus = bonus;
+            let mut troop = troop.get();
+            let unit = &mut troop.unit;
+            for bonus in unit.bonuses() {
+                bonus.on_battle_start(unit, &self);
+            }
+            unit.bonus = unit.get_bonus();
+            if let Some(terrain) = self.place.terrain {
+                for effect in terrain.effects(unit.info.unit_type) {
+                    unit.add_effect(effect);
+                }
+            }
             unit.recalc();
+            troop.battle_stats = TroopBattleStats {
+                all_moves: troop.unit.modified.moves.max(0) as u64,
+                ..Default::default()
+            };
         });
+        self.stats = vec![
+            ArmyBattleStats::new(&armys[self.army1]),
+            ArmyBattleStats::new(&armys[self.army2]),
+        ];
         self.winner = None;
-        self.active_unit = self.search_next_active(&*armys);
+        self.draw = false;
+        self.initiative = self.turn_order(&*armys);
+        self.active_unit = self.search_next_active();
         self.can_interact = search_interactions(self, &*armys);
     }
-    pub fn remove_corpses(&mut self, armys: &mut Vec<Army>) {
-        let army1 = &mut armys[self.army1];
-        remove_corpses(self, &mut army1.troops);
-        army1.recalc_army_hitmap();
-        let army2 = &mut armys[self.army2];
-        remove_corpses(self, &mut army2.troops);
-        army2.recalc_army_hitmap();
+    /// There is a winner or the battle ended in a draw
+    pub fn is_over(&self) -> bool {
+        self.winner.is_some() || self.draw
     }
-    pub fn search_next_active(&self, armys: &Vec<Army>) -> Option<(usize, usize)> {
-        let army1 = &armys[self.army1];
-        let army2 = &armys[self.army2];
-        if self.winner.is_some() {
-            return None;
+    /// Leading army of the other side of the battle
+    pub fn enemy(&self, army: usize) -> usize {
+        if self.side(army) == Some(0) {
+            self.army2
+        } else {
+            self.army1
         }
-		
-        fn max_speed(troops: &Vec<TroopType>) -> (usize, &TroopType) {
-            troops
+    }
+    /// Decides the fate of a prisoner, returns false if the choice can't be made
+    pub fn decide_prisoner(
+        &mut self,
+        armys: &mut Vec<Army>,
+        prisoner: usize,
+        choice: PrisonerChoice,
+    ) -> bool {
+        let (Some(winner), Some(troop)) = (self.winner, self.prisoners.get(prisoner).cloned())
+        else {
+            return false;
+        };
+        match choice {
+            PrisonerChoice::RansomMana => {
+                armys[winner].stats.mana += troop.get().unit.info.surrender.unwrap_or(0);
+            }
+            PrisonerChoice::RansomGold => {
+                armys[winner].stats.gold += troop.get().unit.info.cost_hire;
+            }
+            PrisonerChoice::Recruit => {
+                troop.get().unit.army = winner;
+                if armys[winner].add_troop(troop.clone()).is_err() {
+                    troop.get().unit.army = self.enemy(winner);
+                    return false;
+                }
+            }
+            PrisonerChoice::Release => {
+                armys[self.enemy(winner)].add_troop(troop).ok();
+            }
+        }
+        self.prisoners.remove(prisoner);
+        true
+    }
+    /// Armies fighting for the side, the leading army first
+    pub fn side_armies(&self, side: usize) -> Vec<usize> {
+        let leader = if side == 0 { self.army1 } else { self.army2 };
+        std::iter::once(leader)
+            .chain(
+                self.allies
+                    .iter()
+                    .filter(|(_, ally_side)| *ally_side == side)
+                    .map(|(ally, _)| *ally),
+            )
+            .collect()
+    }
+    pub fn army_stats(&self, army: usize) -> Option<&ArmyBattleStats> {
+        self.stats.get(self.side(army)?)
+    }
+    /// Statistics of every troop that took part in the battle: ones in the armies, corpses and prisoners.
+    /// After `end` the corpses are back in the armies.
+    pub fn troop_stats(&self, armys: &Vec<Army>) -> Vec<(usize, TroopType, TroopBattleStats)> {
+        [self.army1, self.army2]
+            .into_iter()
+            .flat_map(|army| armys[army].troops.iter())
+            .chain(self.dead.iter())
+            .chain(self.prisoners.iter())
+            .map(|troop| {
+                let stats = troop.get().battle_stats;
+                let army = troop.get().unit.army;
+                (army, troop.clone(), stats)
+            })
+            .collect()
+    }
+    /// Index of the army in `stats`, 0 for army1 and 1 for army2, allies take the side they help.
+    /// `None` for an army that doesn't take part in the battle.
+    pub fn side(&self, army: usize) -> Option<usize> {
+        if army == self.army1 {
+            Some(0)
+        } else if army == self.army2 {
+            Some(1)
+        } else {
+            self.allies
+                .iter()
+                .find(|(ally, _)| *ally == army)
+                .map(|(_, side)| *side)
+        }
+    }
+    fn record_hit(&mut self, attacker: usize, target: usize, hit: u64) {
+        let (Some(attacker), Some(target)) = (self.side(attacker), self.side(target)) else {
+            return;
+        };
+        if attacker == target || hit == 0 || self.stats.len() < 2 {
+            return;
+        }
+        let stats = &mut self.stats[attacker];
+        stats.normal_lost_hit += hit;
+        stats.turn_max_hit = stats.turn_max_hit.max(hit);
+        self.stats[target].lost_hit += hit;
+    }
+    pub fn remove_corpses(&mut self, armys: &mut Vec<Army>) {
+        for army in [self.army1, self.army2] {
+            let dead = armys[army]
+                .troops
                 .iter()
                 .enumerate()
-                .max_by(|inf1, inf2| {
-                    let (troop1, troop2) = (inf1.1.get(), inf2.1.get());
-                    let tr1_inactive = troop_inactive(&*troop1);
-                    let tr2_inactive = troop_inactive(&*troop2);
-                    let res = if tr1_inactive {
-                        Less
-                    } else if tr2_inactive {
-                        Greater
-                    } else {
-                        troop1.unit.modified.speed.cmp(&troop2.unit.modified.speed)
-                    };
-                    res
-                })
-                .unwrap()
-        }
-        let troops = &army1.troops;
-        let next1 = max_speed(&troops);
-        let troops = &army2.troops;
-        let next2 = max_speed(&troops);
-        return {
-            let (troop1, troop2) = (&next1.1.get(), &next2.1.get());
-
-            let tr1_inactive = troop_inactive(&troop1);
-            let tr2_inactive = troop_inactive(&troop2);
-            if troop1.unit.modified.speed > troop2.unit.modified.speed && !tr1_inactive {
-                Some((troop1.unit.army, next1.0))
-            } else if !tr2_inactive {
-                Some((troop2.unit.army, next2.0))
-            } else {
-                None
+                .filter(|(_, troop)| troop.get().is_dead())
+                .map(|(index, _)| index)
+                .collect::<Vec<_>>();
+            // Troops behind the corpses move up in the army, their turns follow them
+            let follow = |(at, index): (usize, usize)| {
+                if at != army {
+                    Some((at, index))
+                } else if dead.contains(&index) {
+                    None
+                } else {
+                    Some((at, index - dead.iter().filter(|dead| **dead < index).count()))
+                }
+            };
+            self.initiative = self.initiative.iter().filter_map(|troop| follow(*troop)).collect();
+            self.active_unit = self.active_unit.and_then(follow);
+            let army = &mut armys[army];
+            remove_corpses(self, &mut army.troops);
+            army.recalc_army_hitmap();
+        }
+    }
+    /// Troops that can act in the current round, sorted by their turn
+    pub fn turn_order(&self, armys: &Vec<Army>) -> Vec<(usize, usize)> {
+        let mut order = [self.army1, self.army2]
+            .into_iter()
+            .flat_map(|army| (0..armys[army].troops.len()).map(move |index| (army, index)))
+            .filter(|&troop| queue_troop(armys, troop))
+            .map(|troop| (self.turn_priority(armys, troop), troop))
+            .collect::<Vec<_>>();
+        order.sort_by(|troop1, troop2| troop2.cmp(troop1));
+        order.into_iter().map(|(_, troop)| troop).collect()
+    }
+    /// Place of a queued troop in the turn order, the bigger one goes first
+    fn turn_priority(
+        &self,
+        armys: &Vec<Army>,
+        (army, index): (usize, usize),
+    ) -> (bool, i64, usize, usize) {
+        // The troops which decided to wait go after the others, then by speed.
+        // On equal speed army2 goes first and inside an army the last troop does.
+        let stats = armys[army].troops[index].get().battle_stats;
+        (!stats.waiting, stats.turn_speed, usize::from(army == self.army2), index)
+    }
+    /// Troop that acts next, the first one in `initiative`
+    pub fn search_next_active(&self) -> Option<(usize, usize)> {
+        if self.is_over() {
+            return None;
+        }
+        self.initiative.first().copied()
+    }
+    /// Keeps `initiative` in order after an action without sorting it again. Troops that can't act
+    /// any more leave it, the acting troop and the ones whose speed changed are put back in place.
+    pub fn update_initiative(&mut self, armys: &Vec<Army>) {
+        if self.is_over() {
+            self.initiative.clear();
+            return;
+        }
+        let mut initiative = std::mem::take(&mut self.initiative);
+        let mut moved = Vec::new();
+        initiative.retain(|&(army, index)| {
+            let troop = armys[army].troops[index].get();
+            if troop_inactive(&troop) {
+                return false;
             }
-        };
+            let stays = troop.unit.modified.speed == troop.battle_stats.turn_speed
+                && self.active_unit != Some((army, index));
+            if !stays {
+                moved.push((army, index));
+            }
+            stays
+        });
+        for troop in moved {
+            queue_troop(armys, troop);
+            let priority = self.turn_priority(armys, troop);
+            let at =
+                initiative.partition_point(|other| self.turn_priority(armys, *other) > priority);
+            initiative.insert(at, troop);
+        }
+        self.initiative = initiative;
     }
-    pub fn end(&mut self, armys: &mut Vec<Army>) {
-        fn restore_corpses(armys: &mut Vec<Army>, battle: &mut BattleInfo, _winner: usize) {
+    /// Hands out the results of the battle, only the first call does anything
+    pub fn end(&mut self, armys: &mut Vec<Army>) -> BattleAwards {
+        fn restore_corpses(armys: &mut Vec<Army>, battle: &mut BattleInfo) {
             let mut corpses = Vec::new();
             corpses.append(&mut battle.dead);
             for dead in corpses {
@@ -136,42 +578,56 @@ impl BattleInfo {
                 armys[army].add_troop(dead).ok();
             }
         }
+        // Lent and garrison troops fight inside army1 and army2, so they are reached there too
         fn trigger_end(armys: &mut Vec<Army>, battle: &mut BattleInfo) {
-            for troop in &mut armys[battle.army1].troops {
+            let troops = [battle.army1, battle.army2]
+                .into_iter()
+                .flat_map(|army| armys[army].troops.iter())
+                .chain(battle.dead.iter());
+            for troop in troops {
                 troop.get().on_battle_end();
             }
         }
+        // Goods of the losing side are shared evenly by the winning side,
+        // the leading army gets the gold that can't be divided
         fn move_goods(
             armys: &mut Vec<Army>,
             battle: &mut BattleInfo,
             winner: usize,
-        ) -> (Vec<Item>, u64, u64) {
-            let loose = match winner {
-                winner if winner == battle.army1 => battle.army2,
-                _ => battle.army1,
-            };
+        ) -> (Vec<Item>, u64) {
+            let side = battle.side(winner).expect("Winner has to be one of the fighting armies");
+            let winners = battle.side_armies(side);
             let mut items = Vec::new();
-            items.append(&mut armys[loose].inventory);
-            armys[winner].inventory.append(&mut items.clone());
-            let gold = armys[loose].stats.gold;
-            armys[loose].stats.gold = 0;
-            armys[winner].stats.gold += gold;
-            let mana = armys[loose]
-                .troops
-                .iter()
-                .map(|troop| troop.get().unit.info.surrender)
-                .sum::<Option<u64>>()
-                .unwrap_or(0);
-            armys[winner].stats.mana += mana;
-            {
+            let mut gold = 0;
+            for loose in battle.side_armies(1 - side) {
                 let army = &mut armys[loose];
+                items.append(&mut army.inventory);
+                gold += army.stats.gold;
+                army.stats.gold = 0;
                 army.defeated = true;
             }
-            (items, gold, mana)
+            for (index, item) in items.iter().enumerate() {
+                armys[winners[index % winners.len()]].inventory.push(*item);
+            }
+            let share = gold / winners.len() as u64;
+            for army in &winners {
+                armys[*army].stats.gold += share;
+            }
+            armys[winner].stats.gold += gold % winners.len() as u64;
+            (items, gold)
         }
-        if let Some(winner) = self.winner {
-            move_goods(armys, self, winner);
-            restore_corpses(armys, self, winner);
+        // Living troops that can give up are captured, the main troop never surrenders
+        fn capture_prisoners(armys: &mut Vec<Army>, battle: &mut BattleInfo, loser: usize) -> usize {
+            let army = &mut armys[loser];
+            let (prisoners, troops): (Vec<_>, Vec<_>) = army.troops.drain(..).partition(|troop| {
+                let troop = troop.get();
+                !troop.is_dead() && !troop.is_main && troop.unit.info.surrender > Some(0)
+            });
+            army.troops = troops;
+            army.recalc_army_hitmap();
+            let captured = prisoners.len();
+            battle.prisoners.extend(prisoners);
+            captured
         }
         /*
         // такстические стоимости (силы) армий
@@ -212,6 +668,67 @@ impl BattleInfo {
           end;
         end;
              */
+        fn give_experience(armys: &mut Vec<Army>, battle: &mut BattleInfo) -> Vec<ExpAward> {
+            let mut awards = Vec::new();
+            if battle.stats.len() < 2 {
+                return awards;
+            }
+            let sides = [battle.army1, battle.army2];
+            for (side, army) in sides.into_iter().enumerate() {
+                battle.stats[side].tactic_cost = tactic_cost(&armys[army]);
+            }
+            for (side, army) in sides.into_iter().enumerate() {
+                let enemy = battle.stats[1 - side].clone();
+                let stats = &mut battle.stats[side];
+                // Surrendered army learns nothing from the battle
+                if stats.first_max_units == 0 || battle.surrendered == Some(army) {
+                    continue;
+                }
+                stats.calc_experience(&enemy);
+                let (experience, first_max_units) = (stats.experience, stats.first_max_units);
+                for troop in &armys[army].troops {
+                    let xp = {
+                        let mut troop = troop.get();
+                        if troop.is_dead() {
+                            continue;
+                        }
+                        let xp = troop_experience(experience, first_max_units, &troop);
+                        troop.unit.gain_xp(xp);
+                        xp
+                    };
+                    // Lent troops fight inside the leading army but belong to their ally
+                    let army = battle
+                        .lent
+                        .iter()
+                        .find(|(_, lent)| lent.ptr_eq(troop))
+                        .map_or(army, |(ally, _)| *ally);
+                    awards.push(ExpAward {
+                        army,
+                        troop: troop.clone(),
+                        xp,
+                    });
+                }
+            }
+            awards
+        }
+        let mut awards = BattleAwards::default();
+        if self.ended {
+            return awards;
+        }
+        self.ended = true;
+        trigger_end(armys, self);
+        if let Some(winner) = self.winner {
+            let (items, gold) = move_goods(armys, self, winner);
+            awards.items = items;
+            awards.gold = gold;
+            awards.prisoners = capture_prisoners(armys, self, self.enemy(winner));
+        }
+        // Nobody loses anything in a draw
+        if self.is_over() {
+            restore_corpses(armys, self);
+        }
+        awards.experience = give_experience(armys, self);
+        awards
     }
 }
 
@@ -253,7 +770,7 @@ pub fn search_interactions(
                         let active_troop = &active_troops[active_unit.1].get();
                         let active_unit_unit = &active_troop.unit;
                         if active_unit_unit.can_attack(unit, troop.pos, active_troop.pos) {
-                            Some((0, index))
+                            Some((army, index))
                         } else {
                             None
                         }
@@ -280,7 +797,6 @@ pub fn search_interactions(
     }
     None
 }
-const MAX_MOVES: u64 = 25;
 pub fn remove_corpses(battle: &mut BattleInfo, troops: &mut Vec<TroopType>) {
     let mut i = 0;
     loop {
@@ -296,14 +812,27 @@ pub fn remove_corpses(battle: &mut BattleInfo, troops: &mut Vec<TroopType>) {
 }
 pub fn restore_moves(troops: &mut Vec<TroopType>) {
     for troop in troops {
-        let unit = &mut troop.get().unit;
+        let mut troop = troop.get();
+        let unit = &mut troop.unit;
         unit.tick();
         unit.stats.moves = unit.modified.max_moves;
-        unit.recalc()
+        unit.recalc();
+        troop.battle_stats.all_moves += troop.unit.modified.moves.max(0) as u64;
+        troop.battle_stats.waiting = false;
+    }
+}
+/// Spends a move of the troop without acting, the bonus of the unit may react on it
+pub(crate) fn skip_move(troop: &mut Troop) {
+    troop.battle_stats.free_moves += 1;
+    let unit = &mut troop.unit;
+    unit.stats.moves -= 1;
+    for bonus in unit.bonuses() {
+        bonus.on_move_skip(unit);
     }
+    unit.recalc();
 }
 pub fn next_move(battle: &mut BattleInfo, armys: &mut Vec<Army>) {
-    if battle.winner.is_some() {
+    if battle.is_over() {
         //state.menu_id = Menu::Start as usize;
         battle.end(armys);
         return;
@@ -316,6 +845,7 @@ pub fn next_move(battle: &mut BattleInfo, armys: &mut Vec<Army>) {
     army2.recalc_army_hitmap();
     battle.move_count += 1;
     check_win(battle, armys);
+    battle.initiative = battle.turn_order(armys);
 }
 pub fn check_win(battle: &mut BattleInfo, armys: &Vec<Army>) {
     fn check_army_win(army: &Army) -> bool {
@@ -328,13 +858,31 @@ pub fn check_win(battle: &mut BattleInfo, armys: &Vec<Army>) {
             })
     }
 
-    if battle.move_count == MAX_MOVES {
-        battle.winner = Some(battle.army1);
+    if battle.surrendered.is_some() {
+        return;
+    }
+    if battle.move_count >= battle.rules.max_rounds {
+        let (defender, attacker) = (battle.army1, battle.army2);
+        battle.winner = match battle.rules.timeout {
+            TimeoutRule::AttackerLoses => Some(defender),
+            TimeoutRule::DefenderLoses => Some(attacker),
+            TimeoutRule::Draw => None,
+            TimeoutRule::MorePower => {
+                match tactic_cost(&armys[defender]).cmp(&tactic_cost(&armys[attacker])) {
+                    Greater => Some(defender),
+                    Less => Some(attacker),
+                    Equal => None,
+                }
+            }
+        };
+        battle.draw = battle.winner.is_none();
     }
     if check_army_win(&armys[battle.army1]) {
         battle.winner = Some(battle.army2);
+        battle.draw = false;
     } else if check_army_win(&armys[battle.army2]) {
         battle.winner = Some(battle.army1);
+        battle.draw = false;
     }
 }
 pub fn check_row_fall(battle: &mut BattleInfo, armys: &mut Vec<Army>) {
@@ -379,23 +927,96 @@ pub fn check_row_fall(battle: &mut BattleInfo, armys: &mut Vec<Army>) {
 }
 
 /// Action represents possibilities in a battle, Cell
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[alkahest(Deserialize, Serialize, SerializeRef, Formula)]
 pub enum Action {
     Cell(usize, usize),
     Move(usize, usize, usize),
+    /// Active unit skips the move and takes a defensive stance
+    Defend,
+    /// Active unit acts after the others in this round
+    Wait,
+    /// Army of the active unit gives up, the enemy wins
+    Surrender,
+    /// Active unit uses a potion (item index) from the army inventory on a troop (index) of its army
+    Potion(usize, usize),
+    /// Army of the active unit casts a spell (index) on the troop in a cell (index) of the army
+    /// the spell targets, once per round and without spending a move
+    Spell(usize, usize),
 }
 pub fn move_thing(battle: &mut BattleInfo, armys: &mut Vec<Army>) {
     check_win(battle, &armys);
     check_row_fall(battle, armys);
     battle.remove_corpses(armys);
-    if battle.winner.is_none() {
-        if battle.active_unit == None {
-            next_move(battle, armys);
-            battle.active_unit = battle.search_next_active(&armys);
-        }
+    battle.update_initiative(armys);
+    if !battle.is_over() && battle.initiative.is_empty() {
+        next_move(battle, armys);
+    }
+    battle.active_unit = battle.search_next_active();
+    if !battle.is_over() {
         battle.can_interact = search_interactions(battle, &armys);
     } else {
         //state.menu_id = Menu::Start as usize;
+        battle.initiative.clear();
+    }
+}
+
+/// Troops of the `army` on the `cells` with the cell each one is reached at, the first cell goes first.
+/// Every troop is taken once and the active troop is never among them.
+pub fn pattern_victims(
+    armys: &Vec<Army>,
+    army: usize,
+    cells: &[usize],
+    active_unit: (usize, usize),
+) -> Vec<(usize, usize)> {
+    let mut victims: Vec<(usize, usize)> = Vec::new();
+    for &cell in cells {
+        let Some(Some(index)) = armys[army].hitmap.get(cell).copied() else {
+            continue;
+        };
+        if victims.iter().any(|(victim, _)| *victim == index) || (army, index) == active_unit {
+            continue;
+        }
+        victims.push((index, cell));
     }
+    victims
+}
+
+/// Action of the active troop on one target troop standing at `pos` of the `army`, hits and kills are counted on both sides
+fn strike(
+    battle: &mut BattleInfo,
+    active_unit: (usize, usize),
+    active_troop: &mut Troop,
+    target_troop: &mut Troop,
+    army: usize,
+    pos: usize,
+    reach: Reach,
+) -> Option<ActionResult> {
+    let active_pos = active_troop.pos;
+    let unit1 = &mut active_troop.unit;
+    let unit2 = &mut target_troop.unit;
+    let (attacker_hp, hp) = (unit1.modified.hp.max(0), unit2.modified.hp.max(0));
+    let res = unit1.reach(unit2, UnitPos::from_index(pos), active_pos, reach, &battle);
+    let hit = (hp - unit2.modified.hp.max(0)).max(0) as u64;
+    // Attacker can be hurt back, e.g. by a counterblow
+    let back_hit = (attacker_hp - unit1.modified.hp.max(0)).max(0) as u64;
+    battle.record_hit(active_unit.0, army, hit);
+    battle.record_hit(army, active_unit.0, back_hit);
+    let killed = unit2.is_dead();
+    let died = unit1.is_dead();
+    if killed && !died {
+        for bonus in unit1.bonuses() {
+            bonus.on_kill(unit2, unit1);
+        }
+    }
+    // Every hit is reported on its own, even on a troop already hit in this action
+    unit_events((army, pos), hp, unit2, &mut battle.events);
+    unit_events((active_unit.0, active_pos.into()), attacker_hp, unit1, &mut battle.events);
+    active_troop.battle_stats.record_hit(hit, killed);
+    target_troop.battle_stats.damage_taken += hit;
+    target_troop.battle_stats.record_hit(back_hit, died);
+    active_troop.battle_stats.damage_taken += back_hit;
+    res
 }
 
 fn unit_interaction(
@@ -403,9 +1024,9 @@ fn unit_interaction(
     armys: &mut Vec<Army>,
     pos: usize,
     army: usize,
-) -> (Option<ActionResult>, bool) {
-    if let Some(_) = battle.winner {
-        return (None, false);
+) -> Option<ActionResult> {
+    if battle.is_over() {
+        return None;
     }
     let mut action_result = None;
     let army = if army == 0 {
@@ -414,37 +1035,28 @@ fn unit_interaction(
         battle.army2
     };
 
-    let mut unit_inactive = false;
-
     let Some(active_unit) = battle.active_unit else {
-        battle.active_unit = battle.search_next_active(armys);
         move_thing(battle, armys);
-        return (None, false);
+        return None;
     };
     let Some(target_index) = armys[army].hitmap[pos] else {
         return if army == active_unit.0 {
-            (
-                handle_action(
-                    Action::Move(active_unit.0, active_unit.1, pos),
-                    battle,
-                    armys,
-                )
-                .and_then(|v| Some(v.0)),
-                true,
+            apply_action(
+                Action::Move(active_unit.0, active_unit.1, pos),
+                battle,
+                armys,
             )
+            .and_then(|v| Some(v.0))
         } else {
-            (None, false)
+            None
         };
     };
     if active_unit.0 == army && target_index == active_unit.1 {
         let troop = &mut armys[army].troops[target_index].get();
-        if troop_inactive(&troop) {
-            return (None, false);
+        if !troop_inactive(&troop) {
+            skip_move(troop);
         }
-        let unit = &mut troop.unit;
-        unit.stats.moves -= 1;
-        unit.recalc();
-        return (None, troop_inactive(&troop));
+        return None;
     } else {
         let target_troops = &armys[army].troops;
         let the_troops = {
@@ -456,78 +1068,247 @@ fn unit_interaction(
             )
         };
         let (Some(active_troop), Some(target_troop)) = the_troops else {
-            return (None, unit_inactive);
+            return None;
         };
         let (mut active_troop, mut target_troop) = (active_troop.get(), target_troop.get());
         if troop_inactive(&active_troop) {
-            return (None, true);
-        }
-        let active_unit_index = active_troop.pos.into();
-        let unit1 = &mut active_troop.unit;
-        let unit2 = &mut target_troop.unit;
-        if !unit2.is_dead() {
-            let res = unit1.attack(
-                unit2,
-                UnitPos::from_index(pos),
-                UnitPos::from_index(active_unit_index),
-                &battle,
+            return None;
+        }
+        if !target_troop.unit.is_dead() {
+            let target_pos = UnitPos::from_index(pos);
+            let (_, power) =
+                active_troop.unit.attack_power(&target_troop.unit, target_pos, active_troop.pos);
+            let res = strike(
+                battle,
+                active_unit,
+                &mut active_troop,
+                &mut target_troop,
+                army,
+                pos,
+                Reach::Target,
             );
-            if res.is_some() {
+            if let Some(result) = res {
+                let cells = active_troop.unit.target_pattern(result).cells(target_pos);
+                let reach = Reach::others(result, power);
+                for (index, cell) in pattern_victims(armys, army, &cells, active_unit)
+                    .into_iter()
+                    .skip(1)
+                {
+                    if active_troop.unit.is_dead() {
+                        break;
+                    }
+                    let mut troop = armys[army].troops[index].get();
+                    if !troop.unit.is_dead() {
+                        strike(battle, active_unit, &mut active_troop, &mut troop, army, cell, reach);
+                    }
+                }
+                let unit1 = &mut active_troop.unit;
                 unit1.stats.moves -= 1;
                 unit1.recalc();
-                if unit1.is_dead() || unit1.modified.moves < 1 {
-                    unit_inactive = true;
-                }
+                active_troop.battle_stats.used_moves += 1;
             }
             action_result = res;
         }
     };
-    (action_result, unit_inactive)
+    action_result
 }
 
 /// A method that processes an action with the given battle and gamemap, action is done by currently active unit, it will return None if action is impossible due to game rules.
+/// Everything the action caused is returned as events.
 pub fn handle_action(
     action: Action,
     battle: &mut BattleInfo,
     armys: &mut Vec<Army>,
+) -> (Option<(ActionResult, (usize, usize))>, Vec<BattleEvent>) {
+    let snapshot = BattleSnapshot::new(battle, armys);
+    let res = apply_action(action, battle, armys);
+    if let Some(log) = &mut battle.log {
+        log.push(action, res);
+    }
+    (res, snapshot.events(battle, action))
+}
+/// The active troop moves to a cell of its army where it fits, the cells it stands on count as free
+fn can_move(battle: &BattleInfo, armys: &Vec<Army>, army: usize, troop: usize, to: usize) -> bool {
+    if battle.is_over() || battle.active_unit != Some((army, troop)) || to >= *MAX_TROOPS {
+        return false;
+    }
+    let Some(moving) = armys[army].troops.get(troop) else {
+        return false;
+    };
+    let moving = moving.get();
+    if troop_inactive(&moving) {
+        return false;
+    }
+    let size = moving.unit.info.size;
+    let columns = *MAX_TROOPS / MAX_LINES;
+    let UnitPos(column, row) = UnitPos::from_index(to);
+    if column + size.0 > columns || row + size.1 > MAX_LINES {
+        return false;
+    }
+    let hitmap: Vec<_> = armys[army]
+        .hitmap
+        .iter()
+        .map(|cell| cell.filter(|index| *index != troop))
+        .collect();
+    Army::fit_to(&hitmap, size, columns, MAX_LINES, row, column)
+}
+/// Returns `None` for an action the rules don't allow, e.g. a move of a troop that isn't active
+/// or a cell outside of the battlefield, the battle stays as it was then
+fn apply_action(
+    action: Action,
+    battle: &mut BattleInfo,
+    armys: &mut Vec<Army>,
 ) -> Option<(ActionResult, (usize, usize))> {
     match action {
         Action::Cell(pos, army) => {
-            if let Some(_) = battle.winner {
+            if battle.is_over() || army > 1 || pos >= *MAX_TROOPS {
                 // state.menu_id = Menu::Start as usize;
                 return None;
             }
             let active = battle.active_unit;
             let res = unit_interaction(battle, armys, pos, army);
-            if res.1 {
-                battle.active_unit = battle.search_next_active(&armys);
-            }
             move_thing(battle, armys);
-            res.0.and_then(|v| Some((v, active.unwrap())))
+            res.and_then(|v| Some((v, active.unwrap())))
         }
         Action::Move(army, troop, to) => {
+            if !can_move(battle, armys, army, troop, to) {
+                return None;
+            }
             let army = &mut armys[army];
-            let unit_inactive = {
+            {
                 let troop = &mut army.troops[troop].get();
                 troop.pos = UnitPos::from_index(to);
+                troop.battle_stats.used_moves += 1;
                 let unit = &mut troop.unit;
                 unit.stats.moves -= 1;
                 unit.recalc();
-                unit.modified.moves < 1 || unit.is_dead()
-            };
+            }
             army.recalc_army_hitmap();
             let active = battle.active_unit;
-            if unit_inactive {
-                battle.active_unit = battle.search_next_active(&armys);
-            }
             move_thing(battle, armys);
             Some((ActionResult::Move, active.unwrap()))
         }
+        Action::Defend => {
+            if battle.is_over() {
+                return None;
+            }
+            let active = battle.active_unit?;
+            {
+                let troop = &mut armys[active.0].troops.get(active.1)?.get();
+                if troop_inactive(&troop) {
+                    return None;
+                }
+                skip_move(troop);
+            }
+            move_thing(battle, armys);
+            Some((ActionResult::Defend, active))
+        }
+        Action::Wait => {
+            if battle.is_over() {
+                return None;
+            }
+            let active = battle.active_unit?;
+            {
+                let troop = &mut armys[active.0].troops.get(active.1)?.get();
+                if troop_inactive(&troop) || troop.battle_stats.waiting {
+                    return None;
+                }
+                troop.battle_stats.waiting = true;
+            }
+            move_thing(battle, armys);
+            Some((ActionResult::Wait, active))
+        }
+        Action::Surrender => {
+            if battle.is_over() {
+                return None;
+            }
+            let active = battle.active_unit?;
+            battle.surrendered = Some(active.0);
+            battle.winner = Some(if active.0 == battle.army1 {
+                battle.army2
+            } else {
+                battle.army1
+            });
+            battle.active_unit = None;
+            battle.can_interact = None;
+            battle.initiative.clear();
+            Some((ActionResult::Surrender, active))
+        }
+        Action::Potion(item, target) => {
+            if battle.is_over() {
+                return None;
+            }
+            let active = battle.active_unit?;
+            if troop_inactive(&armys[active.0].troops.get(active.1)?.get()) {
+                return None;
+            }
+            if !armys[active.0].use_potion(item, target) {
+                return None;
+            }
+            {
+                let troop = &mut armys[active.0].troops[active.1].get();
+                troop.battle_stats.used_moves += 1;
+                let unit = &mut troop.unit;
+                unit.stats.moves -= 1;
+                unit.recalc();
+            }
+            move_thing(battle, armys);
+            Some((ActionResult::Buff, active))
+        }
+        Action::Spell(spell, cell) => {
+            if battle.is_over() || cell >= *MAX_TROOPS {
+                return None;
+            }
+            let active = battle.active_unit?;
+            let cast = (active.0, battle.move_count);
+            if battle.spell_casts.contains(&cast) {
+                return None;
+            }
+            let info = get_spell(spell)?;
+            if !info.in_battle() {
+                return None;
+            }
+            let army = match info.target {
+                SpellTarget::Enemy => battle.enemy(active.0),
+                _ => active.0,
+            };
+            let troops = match info.target {
+                SpellTarget::Ally | SpellTarget::Enemy => vec![armys[army].get_troop(cell)?],
+                SpellTarget::Army => armys[army].troops.clone(),
+                SpellTarget::Tile => return None,
+            };
+            let hp: Vec<i64> = troops
+                .iter()
+                .map(|troop| troop.get().unit.modified.hp.max(0))
+                .collect();
+            if !armys[active.0].cast_spell(spell, &troops) {
+                return None;
+            }
+            // Spell damage counts like the hits of the active unit
+            let caster = armys[active.0].troops[active.1].clone();
+            for (troop, hp) in troops.iter().zip(hp) {
+                let (hit, killed) = {
+                    let mut target = troop.get();
+                    let hit = (hp - target.unit.modified.hp.max(0)).max(0) as u64;
+                    target.battle_stats.damage_taken += hit;
+                    (hit, hit > 0 && target.unit.is_dead())
+                };
+                battle.record_hit(active.0, army, hit);
+                caster.get().battle_stats.record_hit(hit, killed);
+            }
+            battle.spell_casts.push(cast);
+            move_thing(battle, armys);
+            if info.target == SpellTarget::Enemy {
+                Some((ActionResult::Debuff, active))
+            } else {
+                Some((ActionResult::Buff, active))
+            }
+        }
     }
 }
 
 /// Used for processing an action in a context of using a server
-pub fn handle_server_action(connection: &mut Option<ConnectionManager>, action: (usize, usize)) {
+pub fn handle_server_action(connection: &mut Option<ConnectionManager>, action: Action) {
     let Some(connection) = connection else {
         return;
     };
@@ -552,12 +1333,9 @@ pub fn handle_server_action(connection: &mut Option<ConnectionManager>, action:
             if Some(army) != battle.active_unit.and_then(|v| Some(v.0)).as_ref() {
                 return;
             }
-            handle_action(
-                Action::Cell(action.0, action.1),
-                battle,
-                &mut connection.gamemap.armys,
-            );
-            let message = ServerMessage::State((Some(battle.clone()), connection.gamemap.clone()));
+            handle_action(action, battle, &mut connection.gamemap.armys);
+            let message =
+                ServerMessage::State((Some(battle.for_clients()), connection.gamemap.clone()));
             let size = serialized_size::<ServerMessage, _>(&message);
             let mut output = vec![0u8; size.0];
             serialize::<ServerMessage, ServerMessage>(message, &mut output).ok();
@@ -573,112 +1351,35 @@ pub fn handle_server_action(connection: &mut Option<ConnectionManager>, action:
 #[cfg(test)]
 mod tests {
     use crate::{
-        battle::ArmyStats,
-        parse::{parse_items, parse_units},
-        units::unitstats::ModifyUnitStats,
+        battle::{
+            ai::{auto_resolve, choose_action, play_pc_turns},
+            preview::preview_attack,
+            replay::*,
+        },
+        parse::parse_items,
+        testing::*,
     };
-    use rand::{seq::IteratorRandom, thread_rng, Rng};
+    use rand::seq::IteratorRandom;
 
     use super::*;
-    fn get_unit(moves: i64, speed: i64, army: usize) -> Unit {
-        let mut unit = Unit {
-            bonus: crate::bonuses::Bonus::NoBonus,
-            stats: UnitStats {
-                speed,
-                hp: 100,
-                max_hp: 100,
-                moves,
-                max_moves: moves,
-                ..Default::default()
-            },
-            modified: UnitStats {
-                speed,
-                ..Default::default()
-            },
-            modify: ModifyUnitStats::default(),
-            info: UnitInfo {
-                name: "".into(),
-                descript: "".into(),
-                cost: 0,
-                cost_hire: 0,
-                icon_index: 0,
-                size: (1, 1),
-                unit_type: UnitType::People,
-                next_unit: Vec::new(),
-                magic_type: None,
-                surrender: None,
-                lvl: LevelUpInfo::empty(),
-            },
-            effects: Vec::new(),
-            lvl: UnitLvl::empty(),
-            inventory: UnitInventory::empty(),
-            army,
-        };
-        unit.recalc();
-        unit
-    }
-    fn gen_army(army_num: usize) -> Army {
-        let mut army = Army::new(
-            vec![],
-            ArmyStats {
-                gold: 0,
-                mana: 0,
-                army_name: String::new(),
-            },
-            vec![],
-            (0, 0),
-            true,
-            crate::battle::control::Control::PC,
-        );
-        for _ in 0..10 {
-            army.add_troop(Troop::new(get_unit(1, thread_rng().gen_range(1..10), army_num)).into())
-                .ok();
-        }
-        army
-    }
-    fn gen_army_from_units(army_num: usize, units: &Vec<Unit>) -> Army {
-        let mut army = Army::new(
-            vec![],
-            ArmyStats {
-                gold: 0,
-                mana: 0,
-                army_name: String::new(),
-            },
-            vec![],
-            (0, 0),
-            true,
-            crate::battle::control::Control::PC,
-        );
-        for _ in 0..10 {
-            army.add_troop(
-                Troop::new({
-                    let mut unit = units.iter().choose(&mut thread_rng()).unwrap().clone();
-                    unit.army = army_num;
-                    unit
-                })
-                .into(),
-            )
-            .ok();
-        }
-        army
-    }
     #[test]
     fn selecting_active() {
         for iteration in 0..100 {
-            let army1 = gen_army(0);
-            let army2 = gen_army(1);
-            let mut armies = vec![army1, army2];
-            let battle = BattleInfo::new(&mut armies, 0, 1);
+            let (armies, mut battle) = gen_battle(iteration);
 
             let mut been = vec![];
-            while let Some(active_unit) = battle.search_next_active(&armies) {
+            while let Some(active_unit) = battle.search_next_active() {
                 if !been.contains(&active_unit) {
                     been.push(active_unit);
                 }
-                let troop = &mut armies[active_unit.0].troops[active_unit.1].get();
-                assert!(!troop_inactive(troop));
-                troop.unit.stats.moves -= 1;
-                troop.unit.recalc();
+                {
+                    let troop = &mut armies[active_unit.0].troops[active_unit.1].get();
+                    assert!(!troop_inactive(troop));
+                    troop.unit.stats.moves -= 1;
+                    troop.unit.recalc();
+                }
+                battle.update_initiative(&armies);
+                battle.active_unit = battle.search_next_active();
             }
             let gen_expectations = |a| (0..10).map(move |v| (a, v));
             let mut expected = gen_expectations(0).chain(gen_expectations(1));
@@ -697,20 +1398,15 @@ mod tests {
     }
     #[test]
     fn process_battles() {
-        let res = parse_units(Some("dt/Units.ini"));
-        let Ok((units, _)) = res else {
-            panic!("Unit parsing error")
-        };
+        let units = game_units();
         let _ = parse_items(Some("dt/Rus_Artefacts.ini"), &"Rus".into());
-        for _ in 0..1000 {
-            let army1 = gen_army_from_units(0, &units);
-            let army2 = gen_army_from_units(1, &units);
-            let mut armys = vec![army1, army2];
-            let mut battle = BattleInfo::new(&mut armys, 0, 1);
-            while battle.winner.is_none() {
+        for seed in 0..1000 {
+            let (mut armys, mut battle) = gen_battle_from_units(&units, seed);
+            while !battle.is_over() {
                 if let Some(interactions) = &battle.can_interact.clone() {
-                    if let Some(interaction) = interactions.iter().choose(&mut thread_rng()) {
-                        unit_interaction(&mut battle, &mut armys, interaction.0, interaction.1);
+                    if let Some(interaction) = interactions.iter().choose(&mut battle.rng) {
+                        let side = (interaction.0 != battle.army1) as usize;
+                        unit_interaction(&mut battle, &mut armys, interaction.1, side);
                     }
                 }
                 move_thing(&mut battle, &mut armys);
@@ -718,4 +1414,760 @@ mod tests {
             battle.end(&mut armys);
         }
     }
+    #[test]
+    fn ai_battles() {
+        let units = game_units();
+        for seed in 0..100 {
+            let (mut armys, mut battle) = gen_battle_from_units(&units, seed);
+            while !battle.is_over() {
+                play_pc_turns(&mut battle, &mut armys);
+                move_thing(&mut battle, &mut armys);
+            }
+            let winner = battle.winner.unwrap();
+            let awards = battle.end(&mut armys);
+            assert!(awards.experience.iter().any(|award| award.army == winner));
+            for award in awards.experience {
+                assert!(award.xp > 0);
+                let lvl = &award.troop.get().unit.lvl;
+                assert!(lvl.xp < lvl.max_xp);
+            }
+        }
+    }
+    #[test]
+    fn seeded_battles_repeat() {
+        let units = game_units();
+        let play = |seed| {
+            let (mut armys, mut battle) = gen_battle_from_units(&units, seed);
+            let mut log = Vec::new();
+            while !battle.is_over() {
+                if let Some(interactions) = &battle.can_interact.clone() {
+                    if let Some(interaction) = interactions.iter().choose(&mut battle.rng) {
+                        log.push(*interaction);
+                        let side = (interaction.0 != battle.army1) as usize;
+                        unit_interaction(&mut battle, &mut armys, interaction.1, side);
+                    }
+                }
+                move_thing(&mut battle, &mut armys);
+            }
+            (log, battle.winner, battle.rng)
+        };
+        for seed in 0..20 {
+            assert_eq!(play(seed), play(seed));
+        }
+    }
+    #[test]
+    fn replay_battles() {
+        let units = game_units();
+        let hits = |armys: &Vec<Army>| {
+            armys
+                .iter()
+                .flat_map(|army| army.troops.iter().map(|troop| troop.get().unit.stats.hp))
+                .collect::<Vec<_>>()
+        };
+        for seed in 0..20 {
+            let (mut armys, mut battle) = gen_battle_from_units(&units, seed);
+            while !battle.is_over() {
+                play_pc_turns(&mut battle, &mut armys);
+                move_thing(&mut battle, &mut armys);
+            }
+            let log = battle.log.clone().unwrap();
+            assert!(!log.actions.is_empty());
+            let log = BattleLog::from_bytes(&log.to_bytes()).unwrap();
+
+            let mut replayer = Replayer::new(&log);
+            assert_eq!(replayer.play(), Ok(()));
+            assert!(replayer.is_finished());
+            assert_eq!(replayer.battle.winner, battle.winner);
+            assert_eq!(hits(&replayer.armys), hits(&armys));
+        }
+    }
+    #[test]
+    fn defend_wait_surrender() {
+        for seed in 0..20 {
+            let (mut armys, mut battle) = gen_battle(seed);
+            let waiting = battle.active_unit.unwrap();
+            assert_eq!(
+                handle_action(Action::Wait, &mut battle, &mut armys).0,
+                Some((ActionResult::Wait, waiting))
+            );
+            while battle.active_unit != Some(waiting) {
+                handle_action(Action::Defend, &mut battle, &mut armys);
+            }
+            for army in &armys {
+                for troop in &army.troops {
+                    let troop = troop.get();
+                    if !troop.battle_stats.waiting {
+                        assert!(troop_inactive(&troop));
+                        assert_eq!(troop.battle_stats.free_moves, 1);
+                    }
+                }
+            }
+            assert_eq!(handle_action(Action::Wait, &mut battle, &mut armys).0, None);
+            handle_action(Action::Defend, &mut battle, &mut armys);
+            assert_eq!(battle.move_count, 1);
+
+            let surrendered = battle.active_unit.unwrap().0;
+            let (_, events) = handle_action(Action::Surrender, &mut battle, &mut armys);
+            assert_eq!(events, vec![BattleEvent::Winner(1 - surrendered)]);
+            assert_eq!(battle.winner, Some(1 - surrendered));
+            let awards = battle.end(&mut armys);
+            assert!(awards.experience.iter().all(|award| award.army != surrendered));
+            assert_eq!(handle_action(Action::Defend, &mut battle, &mut armys).0, None);
+        }
+    }
+    #[test]
+    fn battle_events() {
+        let units = game_units();
+        for seed in 0..20 {
+            let (mut armys, mut battle) = gen_battle_from_units(&units, seed);
+            let mut events = Vec::new();
+            while !battle.is_over() {
+                let action = choose_action(&battle, &armys).unwrap_or(Action::Defend);
+                let (res, new_events) = handle_action(action, &mut battle, &mut armys);
+                events.extend(new_events);
+                if res.is_none() {
+                    events.extend(handle_action(Action::Defend, &mut battle, &mut armys).1);
+                }
+                move_thing(&mut battle, &mut armys);
+            }
+            let deaths = events
+                .iter()
+                .filter(|event| matches!(event, BattleEvent::Death(_)))
+                .count();
+            assert_eq!(deaths, battle.dead.len());
+            let rounds = events
+                .iter()
+                .filter(|event| matches!(event, BattleEvent::NewRound(_)))
+                .count();
+            assert_eq!(rounds as u64, battle.move_count);
+            assert_eq!(events.last(), Some(&BattleEvent::Winner(battle.winner.unwrap())));
+        }
+    }
+    #[test]
+    fn preview_matches_attack() {
+        let units = game_units();
+        let hits = |armys: &Vec<Army>| {
+            armys
+                .iter()
+                .flat_map(|army| army.troops.iter().map(|troop| troop.get().unit.modified.hp))
+                .collect::<Vec<_>>()
+        };
+        for seed in 0..20 {
+            let (mut armys, mut battle) = gen_battle_from_units(&units, seed);
+            while !battle.is_over() {
+                let action = choose_action(&battle, &armys).unwrap_or(Action::Defend);
+                let mut expected = None;
+                if let (Action::Cell(pos, side), Some(active)) = (action, battle.active_unit) {
+                    let army = if side == 0 { battle.army1 } else { battle.army2 };
+                    if let Some(index) = armys[army].hitmap[pos] {
+                        let before = hits(&armys);
+                        let preview = preview_attack(&battle, &armys, active, (army, index));
+                        assert_eq!(before, hits(&armys));
+                        let target = armys[army].troops[index].clone();
+                        let hp = target.get().unit.modified.hp.max(0);
+                        expected = preview.map(|preview| (target, hp, preview));
+                    }
+                }
+                let (res, _) = handle_action(action, &mut battle, &mut armys);
+                if let Some((target, hp, preview)) = expected {
+                    assert_eq!(res.map(|res| res.0), Some(preview.result));
+                    let target = target.get();
+                    assert_eq!(preview.hit, (hp - target.unit.modified.hp.max(0)).max(0) as u64);
+                    assert_eq!(preview.kills, target.is_dead());
+                }
+                if res.is_none() {
+                    handle_action(Action::Defend, &mut battle, &mut armys);
+                }
+                move_thing(&mut battle, &mut armys);
+            }
+        }
+    }
+    #[test]
+    fn initiative_queue() {
+        for seed in 0..20 {
+            let (mut armys, mut battle) = gen_battle(seed);
+            assert_eq!(battle.initiative.len(), 20);
+            assert_eq!(battle.initiative.first().copied(), battle.active_unit);
+            let speeds = battle
+                .initiative
+                .iter()
+                .map(|troop| armys[troop.0].troops[troop.1].get().unit.modified.speed)
+                .collect::<Vec<_>>();
+            assert!(speeds.windows(2).all(|pair| pair[0] >= pair[1]));
+            while battle.initiative.len() > 1 {
+                let rest = battle.initiative[1..].to_vec();
+                handle_action(Action::Defend, &mut battle, &mut armys);
+                assert_eq!(battle.initiative, rest);
+                assert_eq!(battle.initiative.first().copied(), battle.active_unit);
+            }
+            handle_action(Action::Defend, &mut battle, &mut armys);
+            assert_eq!(battle.move_count, 1);
+            assert_eq!(battle.initiative.len(), 20);
+        }
+    }
+    #[test]
+    fn initiative_follows_speed() {
+        use crate::effects::effect::ArtilleryEffect;
+        let (mut armys, mut battle) = gen_battle(0);
+        let slowest = *battle.initiative.last().unwrap();
+        let rest = battle.initiative[1..battle.initiative.len() - 1].to_vec();
+        armys[slowest.0].troops[slowest.1].get().unit.add_effect(ArtilleryEffect {
+            info: EffectInfo { lifetime: 1 },
+        });
+        handle_action(Action::Defend, &mut battle, &mut armys);
+        // Only the troop that got faster changes its place, the others keep their order
+        assert_eq!(battle.initiative[0], slowest);
+        assert_eq!(battle.active_unit, Some(slowest));
+        assert_eq!(battle.initiative[1..], rest);
+    }
+    #[test]
+    fn timeout_rules() {
+        for (timeout, winner) in [
+            (TimeoutRule::AttackerLoses, Some(0)),
+            (TimeoutRule::DefenderLoses, Some(1)),
+            (TimeoutRule::Draw, None),
+            // Both armies cost nothing
+            (TimeoutRule::MorePower, None),
+        ] {
+            let mut rng = GameRng::new(0);
+            let army1 = gen_army(0, &mut rng);
+            let army2 = gen_army(1, &mut rng);
+            let mut armys = vec![army1, army2];
+            armys[0].stats.gold = 10;
+            armys[1].stats.gold = 20;
+            let rules = BattleRules {
+                max_rounds: 2,
+                timeout,
+            };
+            let mut battle = BattleInfo::with_rules(&mut armys, 0, 1, rng.fork(), rules);
+            while !battle.is_over() {
+                handle_action(Action::Defend, &mut battle, &mut armys);
+            }
+            assert_eq!(battle.move_count, 2);
+            assert_eq!(battle.winner, winner);
+            assert_eq!(battle.draw, winner.is_none());
+            let awards = battle.end(&mut armys);
+            if winner.is_none() {
+                assert_eq!(awards.gold, 0);
+                assert_eq!((armys[0].stats.gold, armys[1].stats.gold), (10, 20));
+                assert!(!armys[0].defeated && !armys[1].defeated);
+            } else {
+                assert_eq!(armys[winner.unwrap()].stats.gold, 30);
+            }
+        }
+    }
+    #[test]
+    fn prisoners() {
+        let mut rng = GameRng::new(0);
+        let army1 = gen_army(0, &mut rng);
+        let army2 = gen_army(1, &mut rng);
+        let mut armys = vec![army1, army2];
+        for troop in &armys[1].troops {
+            let mut troop = troop.get();
+            troop.unit.info.surrender = Some(10);
+            troop.unit.info.cost_hire = 20;
+        }
+        armys[1].troops[0].get().is_main = true;
+        let mut battle = BattleInfo::new(&mut armys, 0, 1, rng.fork());
+        battle.surrendered = Some(1);
+        battle.winner = Some(0);
+        armys[0].troops.truncate(5);
+        armys[0].recalc_army_hitmap();
+        let awards = battle.end(&mut armys);
+        assert_eq!(awards.prisoners, 9);
+        assert_eq!(battle.prisoners.len(), 9);
+        assert_eq!(armys[1].troops.len(), 1);
+
+        let mana = armys[0].stats.mana;
+        assert!(battle.decide_prisoner(&mut armys, 0, PrisonerChoice::RansomMana));
+        assert_eq!(armys[0].stats.mana, mana + 10);
+        let gold = armys[0].stats.gold;
+        assert!(battle.decide_prisoner(&mut armys, 0, PrisonerChoice::RansomGold));
+        assert_eq!(armys[0].stats.gold, gold + 20);
+        let recruit = battle.prisoners[0].clone();
+        assert!(battle.decide_prisoner(&mut armys, 0, PrisonerChoice::Recruit));
+        assert_eq!(armys[0].troops.len(), 6);
+        assert_eq!(recruit.get().unit.army, 0);
+        assert!(battle.decide_prisoner(&mut armys, 0, PrisonerChoice::Release));
+        assert_eq!(armys[1].troops.len(), 2);
+        assert_eq!(battle.prisoners.len(), 5);
+        assert!(!battle.decide_prisoner(&mut armys, 5, PrisonerChoice::Release));
+    }
+    #[test]
+    fn troop_battle_stats() {
+        let units = game_units();
+        for seed in 0..20 {
+            let (mut armys, mut battle) = gen_battle_from_units(&units, seed);
+            let troops = armys[0].troops.len() + armys[1].troops.len();
+            while !battle.is_over() {
+                play_pc_turns(&mut battle, &mut armys);
+                move_thing(&mut battle, &mut armys);
+            }
+            let dead = battle.dead.len() as u64;
+            battle.end(&mut armys);
+            let stats = battle.troop_stats(&armys);
+            assert_eq!(stats.len(), troops);
+            let dealt = stats.iter().map(|(_, _, stats)| stats.damage_dealt).sum::<u64>();
+            let taken = stats.iter().map(|(_, _, stats)| stats.damage_taken).sum::<u64>();
+            let kills = stats.iter().map(|(_, _, stats)| stats.kills).sum::<u64>();
+            assert_eq!(dealt, taken);
+            assert!(kills <= dead);
+            for army in [0, 1] {
+                let army_stats = battle.army_stats(army).unwrap();
+                let army_dealt = stats
+                    .iter()
+                    .filter(|(troop_army, _, _)| *troop_army == army)
+                    .map(|(_, _, stats)| stats.damage_dealt)
+                    .sum::<u64>();
+                assert_eq!(army_stats.normal_lost_hit, army_dealt);
+                let max_hit = stats
+                    .iter()
+                    .filter(|(troop_army, _, _)| *troop_army == army)
+                    .map(|(_, _, stats)| stats.max_hit)
+                    .max()
+                    .unwrap_or(0);
+                assert_eq!(army_stats.turn_max_hit, max_hit);
+            }
+        }
+    }
+    #[test]
+    fn siege() {
+        use crate::map::object::MapBuildingdata;
+        for attacker_wins in [false, true] {
+            let mut rng = GameRng::new(attacker_wins as u64);
+            let mut gamemap = GameMap::default();
+            gamemap.armys = vec![gen_army(0, &mut rng), gen_army(1, &mut rng)];
+            gamemap.armys[0].troops.truncate(5);
+            gamemap.armys[0].recalc_army_hitmap();
+            gamemap.armys[0].building = Some(0);
+            for troop in &gamemap.armys[0].troops {
+                troop.get().unit.stats.defence.hand_units = 10;
+            }
+            gamemap.buildings.push(MapBuildingdata {
+                name: String::new(),
+                desc: String::new(),
+                id: 0,
+                event: Vec::new(),
+                market: None,
+                recruitment: None,
+                pos: (0, 0),
+                defense: 50,
+                income: 0,
+                owner: Some(0),
+                garrison: (0..2)
+                    .map(|_| Troop::new(get_unit(1, 5, 2)).into())
+                    .collect(),
+            });
+            let mut battle = gamemap.start_battle(0, 1);
+            assert_eq!(battle.place.siege.map(|siege| siege.building), Some(0));
+            assert_eq!(gamemap.armys[0].troops.len(), 7);
+            assert!(gamemap.buildings[0].garrison.is_empty());
+            assert_eq!(battle.garrison[0].get().unit.army, 0);
+            assert_eq!(
+                gamemap.armys[0].troops[0].get().unit.modified.defence.hand_units,
+                15
+            );
+
+            battle.winner = Some(if attacker_wins { 1 } else { 0 });
+            gamemap.finish_battle(&mut battle);
+            assert_eq!(gamemap.armys[0].troops.len(), 5);
+            if attacker_wins {
+                assert_eq!(gamemap.buildings[0].owner, Some(1));
+                assert!(gamemap.buildings[0].garrison.is_empty());
+            } else {
+                assert_eq!(gamemap.buildings[0].owner, Some(0));
+                assert_eq!(gamemap.buildings[0].garrison.len(), 2);
+            }
+            assert!(battle.place.siege.is_none());
+            if attacker_wins {
+                continue;
+            }
+            // Defence of the siege ends with the battle, the next one starts from the same stats
+            for troop in &gamemap.buildings[0].garrison {
+                assert!(troop.get().unit.effects.is_empty());
+            }
+            let hand_defence = |gamemap: &GameMap| {
+                gamemap.armys[0].troops[0].get().unit.modified.defence.hand_units
+            };
+            assert_eq!(hand_defence(&gamemap), 10);
+            let mut battle = gamemap.start_battle(0, 1);
+            assert_eq!(hand_defence(&gamemap), 15);
+            battle.winner = Some(0);
+            gamemap.finish_battle(&mut battle);
+            assert_eq!(hand_defence(&gamemap), 10);
+        }
+    }
+    #[test]
+    fn terrain_modifiers() {
+        use crate::{
+            map::{terrain::BattleTerrain, tile::TILES},
+            parse::parse_terrain,
+        };
+        parse_terrain(Some("dt/Terrain.ini"));
+        let swamp = TILES
+            .iter()
+            .position(|tile| tile.sprite() == "Swamp.png")
+            .unwrap();
+        let mut rng = GameRng::new(0);
+        let mut armys = vec![gen_army(0, &mut rng), gen_army(1, &mut rng)];
+        for troop in armys.iter().flat_map(|army| army.troops.iter()) {
+            let mut troop = troop.get();
+            troop.unit.stats.speed = 10;
+            troop.unit.stats.damage.ranged = 20;
+        }
+        armys[1].troops[0].get().unit.info.unit_type = UnitType::Undead;
+        let place = BattlePlace {
+            terrain: Some(BattleTerrain {
+                tile: swamp,
+                deco: true,
+            }),
+            siege: None,
+        };
+        // Terrain changes end with the battle and don't pile up in the next one
+        for _ in 0..2 {
+            let mut battle =
+                BattleInfo::with_place(&mut armys, 0, 1, rng.fork(), BattleRules::default(), place);
+            let people = armys[0].troops[0].get().unit.modified;
+            assert_eq!(people.speed, 7);
+            assert_eq!(people.damage.ranged, 15);
+            let undead = armys[1].troops[0].get().unit.modified;
+            assert_eq!(undead.speed, 10);
+            battle.end(&mut armys);
+            let people = armys[0].troops[0].get().unit.modified;
+            assert_eq!(people.speed, 10);
+            assert_eq!(people.damage.ranged, 20);
+        }
+    }
+    #[test]
+    fn allies() {
+        use crate::battle::control::Fraction;
+        let mut rng = GameRng::new(0);
+        let mut gamemap = GameMap::default();
+        let places = [
+            ((5, 5), Fraction::Enemy),
+            ((5, 6), Fraction::Player),
+            ((6, 6), Fraction::Ally),
+            ((4, 5), Fraction::Enemy),
+            ((4, 4), Fraction::Neighbour),
+            ((9, 9), Fraction::Enemy),
+        ];
+        for (index, (pos, fraction)) in places.into_iter().enumerate() {
+            let mut army = gen_army(index, &mut rng);
+            army.troops.truncate(3);
+            army.recalc_army_hitmap();
+            army.pos = pos;
+            army.fraction = fraction;
+            gamemap.armys.push(army);
+        }
+        gamemap.armys[0].stats.gold = 100;
+        gamemap.armys[3].stats.gold = 51;
+
+        let mut battle = gamemap.start_battle(0, 1);
+        assert_eq!(battle.allies, vec![(2, 1), (3, 0)]);
+        assert_eq!(battle.side_armies(0), vec![0, 3]);
+        assert_eq!(battle.enemy(3), 1);
+        assert_eq!(gamemap.armys[0].troops.len(), 6);
+        assert_eq!(gamemap.armys[1].troops.len(), 6);
+        assert!(gamemap.armys[3].troops.is_empty());
+        assert_eq!(gamemap.armys[4].troops.len(), 3);
+
+        battle.winner = Some(1);
+        let awards = battle.end(&mut gamemap.armys);
+        assert_eq!(awards.gold, 151);
+        assert_eq!(gamemap.armys[1].stats.gold, 76);
+        assert_eq!(gamemap.armys[2].stats.gold, 75);
+        assert!(gamemap.armys[3].defeated);
+
+        gamemap.finish_battle(&mut battle);
+        for army in 0..4 {
+            let troops = &gamemap.armys[army].troops;
+            assert_eq!(troops.len(), 3);
+            assert!(troops.iter().all(|troop| troop.get().unit.army == army));
+        }
+        assert!(battle.lent.is_empty());
+        // Allies learn from the battle too and awards find the troops back in their armies
+        for ally in [2, 3] {
+            assert!(awards.experience.iter().any(|award| award.army == ally));
+        }
+        for award in &awards.experience {
+            let troops = &gamemap.armys[award.army].troops;
+            assert!(troops.iter().any(|troop| troop.ptr_eq(&award.troop)));
+        }
+    }
+    #[test]
+    fn auto_resolve_battle() {
+        let units = game_units();
+        for seed in 0..10 {
+            let (mut armys, mut battle) = gen_battle_from_units(&units, seed);
+            armys[0].stats.gold = 10;
+            armys[1].stats.gold = 20;
+            let awards = auto_resolve(&mut battle, &mut armys);
+            assert!(battle.is_over());
+            assert!(battle.dead.is_empty());
+            match battle.winner {
+                Some(winner) => {
+                    let loser = battle.enemy(winner);
+                    assert!(armys[loser].defeated);
+                    assert!(!armys[winner].defeated);
+                    assert_eq!(armys[winner].stats.gold, 30);
+                    assert_eq!(awards.gold, armys[winner].stats.gold - [10, 20][winner]);
+                }
+                None => assert_eq!(awards.gold, 0),
+            }
+        }
+    }
+    #[test]
+    fn target_patterns() {
+        use crate::bonuses::Bonus;
+        let columns = *MAX_TROOPS / 2;
+        let chosen = UnitPos(2, 1);
+        let cell: usize = chosen.into();
+        assert_eq!(TargetPattern::Single.cells(chosen), vec![cell]);
+        let row = TargetPattern::Row.cells(chosen);
+        assert_eq!(row.len(), columns);
+        assert!(row.iter().all(|cell| UnitPos::from_index(*cell).1 == 1));
+        assert_eq!(
+            TargetPattern::Column.cells(chosen),
+            vec![cell, UnitPos(2, 0).into()]
+        );
+        assert_eq!(TargetPattern::Splash.cells(chosen).len(), 4);
+        assert_eq!(TargetPattern::Army.cells(chosen).len(), *MAX_TROOPS);
+
+        for (bonus, struck) in [
+            (Bonus::NoBonus, 1),
+            (Bonus::ColumnStrike, 2),
+            (Bonus::Splash, 4),
+            (Bonus::ManyTargets, columns),
+        ] {
+            let mut rng = GameRng::new(0);
+            let mut attacker = gen_army(0, &mut rng);
+            attacker.troops.clear();
+            attacker.recalc_army_hitmap();
+            let mut unit = get_unit(1, 100, 0);
+            unit.stats.damage.hand = 10;
+            unit.bonus = bonus;
+            unit.recalc();
+            attacker.add_troop(Troop::new(unit).into()).ok();
+            attacker.troops[0].get().pos = chosen;
+            attacker.recalc_army_hitmap();
+            let mut defender = gen_army(1, &mut rng);
+            while defender.add_troop(Troop::new(get_unit(1, 1, 1)).into()).is_ok() {}
+            let mut armys = vec![attacker, defender];
+            let mut battle = BattleInfo::new(&mut armys, 0, 1, rng.fork());
+            assert_eq!(battle.active_unit, Some((0, 0)));
+
+            let (res, events) = handle_action(Action::Cell(cell, 1), &mut battle, &mut armys);
+            assert_eq!(res.map(|res| res.0), Some(ActionResult::Melee));
+            let hurt = armys[1]
+                .troops
+                .iter()
+                .filter(|troop| troop.get().unit.modified.hp < 100)
+                .count();
+            assert_eq!(hurt, struck);
+            let damage = events
+                .iter()
+                .filter(|event| matches!(event, BattleEvent::Damage((1, _), _)))
+                .count();
+            assert_eq!(damage, struck);
+            assert_eq!(armys[0].troops[0].get().battle_stats.used_moves, 1);
+        }
+    }
+    #[test]
+    fn played_battle_finish() {
+        let units = game_units();
+        for seed in 0..10 {
+            let mut rng = GameRng::new(seed);
+            let mut gamemap = GameMap::default();
+            gamemap.armys = vec![
+                gen_army_from_units(0, &units, &mut rng),
+                gen_army_from_units(1, &units, &mut rng),
+            ];
+            for army in &mut gamemap.armys {
+                army.stats.gold = 10;
+                army.inventory.push(Item { index: 48 });
+            }
+            let mut battle = gamemap.start_battle(0, 1);
+            // The way the client and the server play it: actions one by one, no auto_resolve
+            while !battle.is_over() {
+                play_pc_turns(&mut battle, &mut gamemap.armys);
+                move_thing(&mut battle, &mut gamemap.armys);
+            }
+            let awards = gamemap.finish_battle(&mut battle);
+            assert!(battle.ended);
+            assert!(battle.dead.is_empty());
+            let Some(winner) = battle.winner else {
+                continue;
+            };
+            let loser = battle.enemy(winner);
+            assert!(gamemap.armys[loser].defeated);
+            assert!(gamemap.armys[loser].inventory.is_empty());
+            assert_eq!(gamemap.armys[winner].inventory.len(), 2);
+            assert_eq!(gamemap.armys[winner].stats.gold, 20);
+            assert_eq!(awards.gold, 10);
+            assert!(awards
+                .experience
+                .iter()
+                .any(|award| award.army == winner && award.xp > 0));
+
+            // Finishing again hands out nothing
+            let again = gamemap.finish_battle(&mut battle);
+            assert_eq!(again.gold, 0);
+            assert!(again.experience.is_empty());
+            assert_eq!(gamemap.armys[winner].stats.gold, 20);
+        }
+    }
+    #[test]
+    fn invalid_actions_rejected() {
+        let mut rng = GameRng::new(0);
+        let mut armys = vec![gen_army(0, &mut rng), gen_army(1, &mut rng)];
+        for army in &mut armys {
+            army.troops.truncate(3);
+            army.recalc_army_hitmap();
+        }
+        let mut battle = BattleInfo::new(&mut armys, 0, 1, rng.fork());
+        let (army, index) = battle.active_unit.unwrap();
+        let other = (index + 1) % 3;
+        let free = armys[army].hitmap.iter().position(Option::is_none).unwrap();
+        let taken = armys[army]
+            .hitmap
+            .iter()
+            .position(|cell| *cell == Some(other))
+            .unwrap();
+        let pos = armys[army].troops[index].get().pos;
+        for action in [
+            Action::Move(army, other, free),
+            Action::Move(1 - army, index, free),
+            Action::Move(army, index, taken),
+            Action::Move(army, index, *MAX_TROOPS),
+            Action::Move(army, 10, free),
+            Action::Cell(*MAX_TROOPS, 0),
+            Action::Cell(0, 2),
+        ] {
+            assert!(handle_action(action, &mut battle, &mut armys).0.is_none());
+            assert_eq!(battle.active_unit, Some((army, index)));
+            assert_eq!(armys[army].troops[index].get().pos, pos);
+        }
+        let res = handle_action(Action::Move(army, index, free), &mut battle, &mut armys).0;
+        assert_eq!(res, Some((ActionResult::Move, (army, index))));
+    }
+    #[test]
+    fn log_of_battle_armies() {
+        let mut rng = GameRng::new(0);
+        let mut armys = vec![
+            gen_army(0, &mut rng),
+            gen_army(1, &mut rng),
+            gen_army(2, &mut rng),
+        ];
+        let mut battle = BattleInfo::new(&mut armys, 2, 0, rng.fork());
+        assert_eq!(battle.log.as_ref().unwrap().armys.len(), 2);
+        assert!(battle.for_clients().log.is_none());
+        assert!(battle.log.is_some());
+        while !battle.is_over() {
+            play_pc_turns(&mut battle, &mut armys);
+            move_thing(&mut battle, &mut armys);
+        }
+        let mut replayer = Replayer::new(battle.log.as_ref().unwrap());
+        assert_eq!(replayer.play(), Ok(()));
+        assert_eq!(replayer.battle.winner, battle.winner);
+    }
+    #[test]
+    fn pattern_victims_reach() {
+        use crate::{bonuses::Bonus, effects::EffectKind, parse::parse_bonuses};
+        parse_bonuses(Some("dt/Bonuses.ini"));
+        let columns = *MAX_TROOPS / 2;
+        let chosen = UnitPos(2, 1);
+        let cell: usize = chosen.into();
+        let battle_with = |bonus: Bonus, victim_bonus: Bonus| {
+            let mut rng = GameRng::new(0);
+            let mut attacker = gen_army(0, &mut rng);
+            attacker.troops.clear();
+            attacker.recalc_army_hitmap();
+            let mut unit = get_unit(1, 100, 0);
+            unit.stats.damage.hand = 10;
+            unit.bonus = bonus;
+            unit.recalc();
+            attacker.add_troop(Troop::new(unit).into()).ok();
+            attacker.troops[0].get().pos = chosen;
+            attacker.recalc_army_hitmap();
+            let mut defender = gen_army(1, &mut rng);
+            while defender.add_troop(Troop::new(get_unit(1, 1, 1)).into()).is_ok() {}
+            for troop in &defender.troops {
+                let mut troop = troop.get();
+                troop.unit.bonus = victim_bonus;
+                troop.unit.stats.damage.hand = 10;
+                troop.unit.recalc();
+            }
+            let mut armys = vec![attacker, defender];
+            let battle = BattleInfo::new(&mut armys, 0, 1, rng.fork());
+            assert_eq!(battle.active_unit, Some((0, 0)));
+            (armys, battle)
+        };
+
+        // Only the chosen target strikes back
+        let (mut armys, mut battle) = battle_with(Bonus::ManyTargets, Bonus::Counterblow);
+        let target = armys[1].hitmap[cell].unwrap();
+        let preview = preview_attack(&battle, &armys, (0, 0), (1, target)).unwrap();
+        assert_eq!(preview.attacker_hit, 10);
+        assert_eq!(preview.others.len(), columns - 1);
+        handle_action(Action::Cell(cell, 1), &mut battle, &mut armys);
+        assert_eq!(armys[0].troops[0].get().unit.modified.hp, 90);
+        for (index, hit) in preview.others {
+            let hp = armys[1].troops[index].get().unit.modified.hp;
+            assert_eq!((100 - hp) as u64, hit);
+            assert_eq!(hit, 10);
+        }
+
+        // A troop behind the target is hit at its own cell of the back line
+        let (mut armys, mut battle) = battle_with(Bonus::ColumnStrike, Bonus::NoBonus);
+        let behind_cell: usize = UnitPos(2, 0).into();
+        let behind = armys[1].hitmap[behind_cell].unwrap();
+        armys[1].troops[behind].get().unit.bonus = Bonus::from("VenomSkin");
+        handle_action(Action::Cell(cell, 1), &mut battle, &mut armys);
+        assert_eq!(armys[1].troops[behind].get().unit.modified.hp, 90);
+        assert!(!armys[0].troops[0]
+            .get()
+            .unit
+            .has_effect_kind(EffectKind::Poison));
+    }
+    #[test]
+    fn events_reported_where_they_happen() {
+        use crate::effects::{
+            effect::{EffectInfo, Poison},
+            EffectKind,
+        };
+        let (mut armys, mut battle) = gen_battle(0);
+        let active = battle.active_unit.unwrap();
+        let enemy = battle.enemy(active.0);
+        let attacker = armys[active.0].troops[active.1].clone();
+        let target = armys[enemy].troops[0].clone();
+        let cell: usize = target.get().pos.into();
+        target.get().unit.add_effect(Poison {
+            info: EffectInfo { lifetime: 2 },
+        });
+
+        let snapshot = BattleSnapshot::new(&battle, &armys);
+        let power = Power {
+            hand: 10,
+            ..Power::empty()
+        };
+        for _ in 0..2 {
+            let reach = Reach::Damage(ActionResult::Melee, power);
+            let (mut attacker, mut target) = (attacker.get(), target.get());
+            strike(&mut battle, active, &mut attacker, &mut target, enemy, cell, reach);
+        }
+        assert!(target.get().unit.add_effect(Poison {
+            info: EffectInfo { lifetime: 5 },
+        }));
+        let events = snapshot.events(&mut battle, Action::Cell(cell, 1));
+        let at = (enemy, cell);
+        let hits: Vec<_> = events
+            .iter()
+            .filter(|event| matches!(event, BattleEvent::Damage(hit_at, _) if *hit_at == at))
+            .collect();
+        assert_eq!(hits, vec![&BattleEvent::Damage(at, 10); 2]);
+        // The refreshed poison is reported, the one put before the action is not
+        let applied = BattleEvent::EffectApplied(at, EffectKind::Poison);
+        assert_eq!(events.iter().filter(|event| **event == applied).count(), 1);
+        assert!(battle.events.is_empty());
+    }
 }
@@ -1,5 +1,6 @@
 use advini::{Ini, IniParseError};
 use alkahest::*;
+use std::str::FromStr;
 #[derive(Clone, Debug)]
 #[alkahest(Deserialize, Serialize, SerializeRef, Formula)]
 pub struct Relations {
@@ -32,9 +33,50 @@ impl Ini for Relations {
 }
 impl Default for Relations {
 	fn default() -> Self {
-		Self { player: 0, ally: 0, neighbour: 128, enemy: 255 }
+		Self::new(0, 0, 128, 255)
 	}
 }
+impl Relations {
+    pub const fn new(player: u8, ally: u8, neighbour: u8, enemy: u8) -> Self {
+        Self {
+            player,
+            ally,
+            neighbour,
+            enemy,
+        }
+    }
+    /// How hostile the group is to the fraction, 0 is a friend and 255 is an enemy
+    pub fn to(&self, fraction: Fraction) -> u8 {
+        match fraction {
+            Fraction::Player => self.player,
+            Fraction::Ally => self.ally,
+            Fraction::Neighbour => self.neighbour,
+            Fraction::Enemy => self.enemy,
+        }
+    }
+}
+/// Group of armies on the map, relations between the groups decide who helps whom in battles
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+#[alkahest(Deserialize, Serialize, SerializeRef, Formula)]
+pub enum Fraction {
+    Player,
+    Ally,
+    Neighbour,
+    #[default]
+    Enemy,
+}
+impl FromStr for Fraction {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim() {
+            "Player" => Ok(Self::Player),
+            "Ally" => Ok(Self::Ally),
+            "Neighbour" => Ok(Self::Neighbour),
+            "Enemy" => Ok(Self::Enemy),
+            fraction => Err(format!("Unknown fraction {fraction}")),
+        }
+    }
+}
 #[derive(Clone, Debug, Default)]
 #[alkahest(Deserialize, Serialize, SerializeRef, Formula)]
 pub enum Control {
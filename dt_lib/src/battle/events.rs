@@ -0,0 +1,162 @@
+use crate::{
+    battle::{
+        army::{Army, TroopType, MAX_TROOPS},
+        battlefield::{Action, BattleInfo},
+    },
+    effects::effect::EffectKind,
+    units::unit::Unit,
+};
+use alkahest::alkahest;
+
+/// Something that happened in a battle, troops are referred by (army, cell index)
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[alkahest(Deserialize, Serialize, SerializeRef, Formula)]
+pub enum BattleEvent {
+    /// Troop lost hits
+    Damage((usize, usize), u64),
+    /// Troop restored hits
+    Heal((usize, usize), u64),
+    Death((usize, usize)),
+    EffectApplied((usize, usize), EffectKind),
+    EffectExpired((usize, usize), EffectKind),
+    /// Back row of the army stepped forward to the empty front row
+    RowFall(usize),
+    /// Number of the round that has started
+    NewRound(u64),
+    Winner(usize),
+    Draw,
+}
+
+/// Effect put on a unit or taken off it, units keep them until the battle turns them into events
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[alkahest(Deserialize, Serialize, SerializeRef, Formula)]
+pub enum EffectChange {
+    Applied(EffectKind),
+    Expired(EffectKind),
+}
+
+/// Events of a unit at `at` that had `hp` hits before, its effect changes are taken
+pub(crate) fn unit_events(
+    at: (usize, usize),
+    hp: i64,
+    unit: &mut Unit,
+    events: &mut Vec<BattleEvent>,
+) {
+    let now = unit.modified.hp.max(0);
+    if now < hp {
+        events.push(BattleEvent::Damage(at, (hp - now) as u64));
+    } else if now > hp {
+        events.push(BattleEvent::Heal(at, (now - hp) as u64));
+    }
+    events.extend(unit.effect_changes.drain(..).map(|change| match change {
+        EffectChange::Applied(kind) => BattleEvent::EffectApplied(at, kind),
+        EffectChange::Expired(kind) => BattleEvent::EffectExpired(at, kind),
+    }));
+}
+
+struct TroopSnapshot {
+    troop: TroopType,
+    army: usize,
+    index: usize,
+    pos: usize,
+    hp: i64,
+    dead: bool,
+}
+impl TroopSnapshot {
+    fn new(troop: &TroopType, army: usize, index: usize) -> Self {
+        let (pos, hp, dead) = {
+            let mut troop = troop.get();
+            // Changes made outside of actions are not reported
+            troop.unit.effect_changes.clear();
+            (troop.pos.into(), troop.unit.modified.hp.max(0), troop.is_dead())
+        };
+        Self {
+            troop: troop.clone(),
+            army,
+            index,
+            pos,
+            hp,
+            dead,
+        }
+    }
+}
+/// State of a battle before an action. Hits and effects are reported where they happen,
+/// comparing the snapshot to the state after the action gives the rest of the events.
+pub struct BattleSnapshot {
+    troops: Vec<TroopSnapshot>,
+    move_count: u64,
+    winner: Option<usize>,
+    draw: bool,
+}
+impl BattleSnapshot {
+    pub fn new(battle: &BattleInfo, armys: &Vec<Army>) -> Self {
+        let troops = [battle.army1, battle.army2]
+            .into_iter()
+            .flat_map(|army| {
+                armys[army]
+                    .troops
+                    .iter()
+                    .enumerate()
+                    .map(move |(index, troop)| TroopSnapshot::new(troop, army, index))
+            })
+            .collect();
+        Self {
+            troops,
+            move_count: battle.move_count,
+            winner: battle.winner,
+            draw: battle.draw,
+        }
+    }
+    /// Events the action reported followed by the rest that led from the snapshot
+    /// to the current state of the battle
+    pub fn events(&self, battle: &mut BattleInfo, action: Action) -> Vec<BattleEvent> {
+        let mut events = std::mem::take(&mut battle.events);
+        let reported = events.len();
+        let mut fallen_armys = Vec::new();
+        for before in &self.troops {
+            let mut troop = before.troop.get();
+            let pos: usize = troop.pos.into();
+            let at = (before.army, pos);
+            // Hits already reported for the cell the troop stood on
+            let hp = before.hp
+                + events[..reported]
+                    .iter()
+                    .map(|event| match event {
+                        BattleEvent::Damage(at, hit) if *at == (before.army, before.pos) => {
+                            -(*hit as i64)
+                        }
+                        BattleEvent::Heal(at, heal) if *at == (before.army, before.pos) => {
+                            *heal as i64
+                        }
+                        _ => 0,
+                    })
+                    .sum::<i64>();
+            unit_events(at, hp, &mut troop.unit, &mut events);
+
+            if troop.is_dead() && !before.dead {
+                events.push(BattleEvent::Death(at));
+            }
+            let moved = match action {
+                Action::Move(army, index, _) => army == before.army && index == before.index,
+                _ => false,
+            };
+            if !moved
+                && pos == before.pos + *MAX_TROOPS / 2
+                && !fallen_armys.contains(&before.army)
+            {
+                fallen_armys.push(before.army);
+            }
+        }
+        events.extend(fallen_armys.into_iter().map(BattleEvent::RowFall));
+        events.extend((self.move_count + 1..=battle.move_count).map(BattleEvent::NewRound));
+        if self.winner.is_none() {
+            if let Some(winner) = battle.winner {
+                events.push(BattleEvent::Winner(winner));
+            }
+        }
+        if !self.draw && battle.draw {
+            events.push(BattleEvent::Draw);
+        }
+        events
+    }
+}
@@ -1,5 +1,9 @@
+pub mod ai;
 pub mod army;
 pub mod battlefield;
+pub mod events;
+pub mod preview;
+pub mod replay;
 pub mod troop;
 pub mod control;
 pub use army::*;
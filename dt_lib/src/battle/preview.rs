@@ -0,0 +1,125 @@
+use crate::{
+    battle::{
+        army::Army,
+        battlefield::{pattern_victims, BattleInfo},
+        events::EffectChange,
+    },
+    effects::effect::EffectKind,
+    units::unit::{ActionResult, MagicType, Power, Reach, UnitPos, UnitType},
+};
+
+/// What an action of one troop on another would do, it is computed on copies of the units
+#[derive(Clone, Debug, PartialEq)]
+pub struct AttackPreview {
+    pub result: ActionResult,
+    /// Damage per channel after the defence of the target and the bonuses of both sides
+    pub damage: Power,
+    /// Hits the target would lose
+    pub hit: u64,
+    /// Hits the target would restore
+    pub heal: u64,
+    /// The target would die, attacks have no randomness in the rules yet
+    pub kills: bool,
+    /// Effects the target would get
+    pub effects: Vec<EffectKind>,
+    /// Hits the attacker would lose, e.g. from a counterblow
+    pub attacker_hit: u64,
+    pub attacker_dies: bool,
+    /// Other troops of the target army the pattern of the action reaches with the hits they would lose
+    pub others: Vec<(usize, u64)>,
+}
+
+/// Shows what the `attacker` troop would do to the `target` troop, both are given as (army, troop index).
+/// Neither the battle nor the armies are changed. Returns None if the attacker can't act on the target.
+pub fn preview_attack(
+    battle: &BattleInfo,
+    armys: &Vec<Army>,
+    attacker: (usize, usize),
+    target: (usize, usize),
+) -> Option<AttackPreview> {
+    if attacker == target {
+        return None;
+    }
+    let (attacker_id, target_army) = (attacker, target.0);
+    let (mut attacker, attacker_pos) = {
+        let troop = armys.get(attacker.0)?.troops.get(attacker.1)?.get();
+        (troop.unit.clone(), troop.pos)
+    };
+    let (mut target, target_pos) = {
+        let troop = armys.get(target.0)?.troops.get(target.1)?.get();
+        (troop.unit.clone(), troop.pos)
+    };
+    if attacker.is_dead() || target.is_dead() {
+        return None;
+    }
+    let attacker_hp = attacker.modified.hp.max(0);
+    let target_hp = target.modified.hp.max(0);
+    target.effect_changes.clear();
+
+    let (channel, power) = attacker.attack_power(&target, target_pos, attacker_pos);
+    let mut damage = if attacker.army != target.army {
+        let mut power = power;
+        // Magic attacks double life magic on the undead
+        if channel.is_none()
+            && target.info.unit_type == UnitType::Undead
+            && matches!(attacker.info.magic_type, Some(MagicType::Life(_)))
+        {
+            power.magic *= 2;
+        }
+        target.clone().corrected_damage(
+            &power,
+            &mut attacker.clone(),
+            target_pos,
+            attacker_pos,
+            battle,
+        )
+    } else {
+        Power::empty()
+    };
+    let result = attacker.reach(&mut target, target_pos, attacker_pos, Reach::Target, battle)?;
+    let target_dies = target.is_dead();
+
+    let mut others = Vec::new();
+    let cells = attacker.target_pattern(result).cells(target_pos);
+    let reach = Reach::others(result, power);
+    for (index, cell) in pattern_victims(armys, target_army, &cells, attacker_id)
+        .into_iter()
+        .skip(1)
+    {
+        if attacker.is_dead() {
+            break;
+        }
+        let mut victim = armys[target_army].troops[index].get().unit.clone();
+        if victim.is_dead() {
+            continue;
+        }
+        let hp = victim.modified.hp.max(0);
+        attacker.reach(&mut victim, UnitPos::from_index(cell), attacker_pos, reach, battle);
+        others.push((index, (hp - victim.modified.hp.max(0)).max(0) as u64));
+    }
+
+    let hp = target.modified.hp.max(0);
+    let hit = (target_hp - hp).max(0) as u64;
+    if hit == 0 {
+        // Curses replace the damage
+        damage = Power::empty();
+    }
+    Some(AttackPreview {
+        result,
+        damage,
+        hit,
+        heal: (hp - target_hp).max(0) as u64,
+        kills: target_dies,
+        effects: target
+            .effect_changes
+            .iter()
+            .filter_map(|change| match change {
+                EffectChange::Applied(kind) => Some(*kind),
+                EffectChange::Expired(_) => None,
+            })
+            .collect(),
+        attacker_hit: (attacker_hp - attacker.modified.hp.max(0)).max(0) as u64,
+        attacker_dies: attacker.is_dead(),
+        others,
+    })
+}
@@ -0,0 +1,129 @@
+use crate::{
+    battle::{
+        army::Army,
+        battlefield::{handle_action, Action, BattleInfo, BattlePlace, BattleRules},
+    },
+    rng::GameRng,
+    units::unit::ActionResult,
+};
+use alkahest::{alkahest, deserialize, serialize, serialized_size};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[alkahest(Deserialize, Serialize, SerializeRef, Formula)]
+pub struct LoggedAction {
+    pub action: Action,
+    pub result: Option<(ActionResult, (usize, usize))>,
+}
+
+/// Everything needed to play a battle again: armies and rng as they were before the start and every action made
+#[derive(Clone, Debug, Default)]
+#[alkahest(Deserialize, Serialize, SerializeRef, Formula)]
+pub struct BattleLog {
+    pub army1: usize,
+    pub army2: usize,
+    pub rng: GameRng,
+    pub rules: BattleRules,
+    pub place: BattlePlace,
+    /// Only army1 and army2, the rest of the map doesn't take part
+    pub armys: Vec<Army>,
+    pub actions: Vec<LoggedAction>,
+}
+impl BattleLog {
+    pub fn new(
+        armys: &Vec<Army>,
+        army1: usize,
+        army2: usize,
+        rng: GameRng,
+        rules: BattleRules,
+        place: BattlePlace,
+    ) -> Self {
+        Self {
+            army1,
+            army2,
+            rng,
+            rules,
+            place,
+            armys: vec![armys[army1].deep_clone(), armys[army2].deep_clone()],
+            actions: Vec::new(),
+        }
+    }
+    pub fn push(&mut self, action: Action, result: Option<(ActionResult, (usize, usize))>) {
+        self.actions.push(LoggedAction { action, result });
+    }
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let size = serialized_size::<BattleLog, _>(self);
+        let mut output = vec![0u8; size.0];
+        serialize::<BattleLog, BattleLog>(self.clone(), &mut output).ok();
+        output
+    }
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        deserialize::<BattleLog, BattleLog>(bytes).ok()
+    }
+}
+
+/// A recorded action gave another result while replaying
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ReplayMismatch {
+    pub step: usize,
+    pub action: Action,
+    pub expected: Option<(ActionResult, (usize, usize))>,
+    pub got: Option<(ActionResult, (usize, usize))>,
+}
+
+/// Plays a `BattleLog` again action by action, checking every result against the recorded one
+pub struct Replayer {
+    pub battle: BattleInfo,
+    pub armys: Vec<Army>,
+    actions: Vec<LoggedAction>,
+    step: usize,
+}
+impl Replayer {
+    pub fn new(log: &BattleLog) -> Self {
+        // Armies keep their indexes on the map, the actions refer to them
+        let mut armys = vec![Army::default(); log.army1.max(log.army2) + 1];
+        for (index, army) in [log.army1, log.army2].into_iter().zip(&log.armys) {
+            armys[index] = army.deep_clone();
+        }
+        let battle = BattleInfo::with_place(
+            &mut armys,
+            log.army1,
+            log.army2,
+            log.rng,
+            log.rules,
+            log.place,
+        );
+        Self {
+            battle,
+            armys,
+            actions: log.actions.clone(),
+            step: 0,
+        }
+    }
+    pub fn is_finished(&self) -> bool {
+        self.step >= self.actions.len()
+    }
+    /// Plays the next recorded action, None if there are no actions left
+    pub fn step(&mut self) -> Option<Result<(), ReplayMismatch>> {
+        let logged = *self.actions.get(self.step)?;
+        let got = handle_action(logged.action, &mut self.battle, &mut self.armys).0;
+        let step = self.step;
+        self.step += 1;
+        if got == logged.result {
+            Some(Ok(()))
+        } else {
+            Some(Err(ReplayMismatch {
+                step,
+                action: logged.action,
+                expected: logged.result,
+                got,
+            }))
+        }
+    }
+    /// Plays all remaining actions, stops at the first mismatch
+    pub fn play(&mut self) -> Result<(), ReplayMismatch> {
+        while let Some(res) = self.step() {
+            res?;
+        }
+        Ok(())
+    }
+}
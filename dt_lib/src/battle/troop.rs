@@ -4,6 +4,33 @@ use crate::{battle::army::Army, bonuses::Bonus, effects::effect::EffectTrait, un
 use alkahest::alkahest;
 use std::fmt::{Debug, Display, Formatter};
 
+/// How the troop spent its moves during the current battle
+#[derive(Clone, Copy, Debug, Default)]
+#[alkahest(Deserialize, Serialize, SerializeRef, Formula)]
+pub struct TroopBattleStats {
+    pub used_moves: u64,
+    pub all_moves: u64,
+    pub free_moves: u64,
+    /// The troop decided to act after the others in the current round
+    pub waiting: bool,
+    /// Speed the troop got its place in `BattleInfo::initiative` with
+    pub turn_speed: i64,
+    pub damage_dealt: u64,
+    pub damage_taken: u64,
+    pub kills: u64,
+    /// The strongest single hit of the troop
+    pub max_hit: u64,
+}
+impl TroopBattleStats {
+    pub fn record_hit(&mut self, hit: u64, killed: bool) {
+        self.damage_dealt += hit;
+        self.max_hit = self.max_hit.max(hit);
+        if killed {
+            self.kills += 1;
+        }
+    }
+}
+
 #[derive(Clone)]
 #[alkahest(Deserialize, Serialize, SerializeRef, Formula)]
 pub struct Troop {
@@ -12,6 +39,7 @@ pub struct Troop {
     pub is_main: bool,
     pub pos: UnitPos,
     pub custom_name: Option<String>,
+    pub battle_stats: TroopBattleStats,
     pub unit: Unit,
 }
 impl Debug for Troop {
@@ -31,6 +59,7 @@ impl Troop {
             is_main: false,
             pos: UnitPos::from_index(0),
             custom_name: None,
+            battle_stats: TroopBattleStats::default(),
             unit,
         }
     }
@@ -43,19 +72,19 @@ impl Troop {
     pub fn on_hour(&self, army: &mut Army) -> bool {
         true
     }
+    /// Effects that last until the end of the battle run out and are removed
     pub fn on_battle_end(&mut self) {
         let unit = &mut self.unit;
-        let mut i = 0;
-        loop {
-            if unit.effects[i].on_battle_end() && unit.effects[i].is_dead() {
-                let mut effect = unit.effects.remove(i);
+        let mut effects = std::mem::take(&mut unit.effects);
+        for effect in effects.iter_mut() {
+            effect.on_battle_end();
+        }
+        for mut effect in effects {
+            if effect.is_dead() {
                 effect.kill(unit);
-                i -= 1;
-            };
-            if i + 1 >= unit.effects.len() {
-                break;
+            } else {
+                unit.effects.push(effect);
             }
-            i += 1;
         }
         unit.recalc();
     }
@@ -69,6 +98,7 @@ impl Troop {
             is_main: false,
             pos: UnitPos::from_index(0),
             custom_name: None,
+            battle_stats: TroopBattleStats::default(),
             unit: Unit {
                 stats: UnitStats::empty(),
                 modified: UnitStats::empty(),
@@ -79,6 +109,7 @@ impl Troop {
                 modify: ModifyUnitStats::default(),
                 bonus: Bonus::NoBonus,
                 effects: vec![],
+                effect_changes: vec![],
             },
         }
     }
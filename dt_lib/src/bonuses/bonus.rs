@@ -1,5 +1,6 @@
 use crate::{
     battle::{army::Army, battlefield::BattleInfo},
+    bonuses::custom::{custom_bonus_index, get_custom_bonus, BonusHook},
     effects::effect::*,
     time::time::Time,
     units::{
@@ -42,7 +43,10 @@ pub enum Bonus {
     FlankStrike,
     Merchant,
     ArmyMedic,
-    Custom = { u32::MAX - 1 },
+    ColumnStrike,
+    Splash,
+    /// Index of the definition in `CUSTOM_BONUSES`
+    Custom(usize) = { u32::MAX - 1 },
     NoBonus = u32::MAX,
 }
 impl Bonus {
@@ -118,10 +122,6 @@ impl Bonus {
                     damage
                 }
             }
-            Self::Counterblow => {
-                sender.attack(receiver, receiver_pos, sender_pos, battle);
-                damage
-            }
             Self::Stealth => {
                 if receiver.modified.moves == receiver.modified.max_moves {
                     Power::empty()
@@ -129,6 +129,13 @@ impl Bonus {
                     damage
                 }
             }
+            Self::Custom(index) => match get_custom_bonus(*index) {
+                Some(bonus) if bonus.works(BonusHook::Attacked, sender, Some(receiver_pos)) => {
+                    bonus.trigger(receiver, Some(sender));
+                    bonus.correct_damage(damage)
+                }
+                _ => damage,
+            },
             _ => damage,
         }
     }
@@ -197,6 +204,13 @@ impl Bonus {
                     damage
                 }
             }
+            Self::Custom(index) => match get_custom_bonus(*index) {
+                Some(bonus) if bonus.works(BonusHook::Attacking, receiver, Some(sender_pos)) => {
+                    bonus.trigger(sender, Some(receiver));
+                    bonus.correct_damage(damage)
+                }
+                _ => damage,
+            },
             _ => damage,
         }
     }
@@ -217,11 +231,48 @@ impl Bonus {
                 });
                 true
             }
+            Self::Custom(index) => match get_custom_bonus(*index) {
+                Some(bonus) if bonus.works(BonusHook::Kill, receiver, None) => {
+                    bonus.trigger(sender, Some(receiver));
+                    true
+                }
+                _ => false,
+            },
+            _ => false,
+        }
+    }
+    /// The target of an action strikes back, the other troops the action reaches don't
+    pub fn on_struck(
+        &self,
+        receiver: &mut Unit,
+        sender: &mut Unit,
+        receiver_pos: UnitPos,
+        sender_pos: UnitPos,
+        battle: &BattleInfo,
+    ) -> bool {
+        match self {
+            Self::Counterblow => {
+                receiver.attack(sender, sender_pos, receiver_pos, battle).is_some()
+            }
+            _ => false,
+        }
+    }
+    /// Runs a custom bonus for a hook without another unit involved
+    fn trigger_custom(&self, hook: BonusHook, unit: &mut Unit) -> bool {
+        match self {
+            Self::Custom(index) => match get_custom_bonus(*index) {
+                Some(bonus) if bonus.works(hook, unit, None) => {
+                    bonus.trigger(unit, None);
+                    true
+                }
+                _ => false,
+            },
             _ => false,
         }
     }
     pub fn on_tick(&self, unit: &mut Unit) -> bool {
         match self {
+            Self::Custom(_) => self.trigger_custom(BonusHook::Tick, unit),
             _ => false,
         }
     }
@@ -259,6 +310,7 @@ impl Bonus {
                     ..Default::default()
                 },
             }),
+            Self::Custom(_) => self.trigger_custom(BonusHook::BattleStart, unit),
             _ => false,
         }
     }
@@ -278,6 +330,7 @@ impl Bonus {
                 });
                 true
             }
+            Self::Custom(_) => self.trigger_custom(BonusHook::MoveSkip, unit),
             _ => false,
         }
     }
@@ -313,7 +366,9 @@ impl Bonus {
             Self::GodAnger => ("bonus_godanger", "bonus_godanger_desc"),
             Self::GodStrike => ("bonus_godstrike", "bonus_godstrike_desc"),
             Self::Invulrenable => ("bonus_invulrenable", "bonus_invulrenable_desc"),
-            Self::ManyTargets => ("bonus_manytargets", "bonus_manytargets"),
+            Self::ManyTargets => ("bonus_manytargets", "bonus_manytargets_desc"),
+            Self::ColumnStrike => ("bonus_columnstrike", "bonus_columnstrike_desc"),
+            Self::Splash => ("bonus_splash", "bonus_splash_desc"),
             Self::Merchant => ("bonus_merchant", "bonus_merchant"),
             Self::PoisonAttack => ("bonus_poison", "bonus_poison_desc"),
             _ => ("", ""),
@@ -363,8 +418,10 @@ impl From<&str> for Bonus {
             "Merchant" => Self::Merchant,
             "ArmyMedic" => Self::ArmyMedic,
             "FlankStrike" => Self::FlankStrike,
-            "Custom" => Self::Custom,
-            "NoBonus" | _ => Self::NoBonus,
+            "ColumnStrike" => Self::ColumnStrike,
+            "Splash" => Self::Splash,
+            "NoBonus" => Self::NoBonus,
+            name => custom_bonus_index(name).map_or(Self::NoBonus, Self::Custom),
         }
     }
 }
@@ -0,0 +1,199 @@
+use crate::{
+    battle::{
+        army::MAX_TROOPS,
+        battlefield::{field_type, Field},
+    },
+    effects::effect::{BonusEffect, Effect, EffectInfo, EffectKind},
+    items::item::{magic_relates, MagicVariants},
+    units::{
+        unit::{Power, Unit, UnitPos, UnitType},
+        unitstats::ModifyUnitStats,
+    },
+};
+use math_thingies::Percent;
+use once_cell::sync::Lazy;
+use std::str::FromStr;
+use tracing_mutex::stdsync::TracingMutex as Mutex;
+
+/// Moment a custom bonus works at
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum BonusHook {
+    BattleStart,
+    Attacked,
+    Attacking,
+    Kill,
+    Tick,
+    MoveSkip,
+}
+impl FromStr for BonusHook {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim() {
+            "BattleStart" => Ok(Self::BattleStart),
+            "Attacked" => Ok(Self::Attacked),
+            "Attacking" => Ok(Self::Attacking),
+            "Kill" => Ok(Self::Kill),
+            "Tick" => Ok(Self::Tick),
+            "MoveSkip" => Ok(Self::MoveSkip),
+            hook => Err(format!("Unknown bonus hook {hook}")),
+        }
+    }
+}
+
+/// Checked on the other unit of an attack or a kill, on the owner of the bonus otherwise
+#[derive(Clone, Debug, Default)]
+pub struct BonusCondition {
+    pub unit_type: Option<UnitType>,
+    pub magic: Option<MagicVariants>,
+    /// Field of the owner, checked only in attacks
+    pub field: Option<Field>,
+}
+impl BonusCondition {
+    fn check(&self, unit: &Unit, owner_pos: Option<UnitPos>) -> bool {
+        self.unit_type
+            .map_or(true, |unit_type| unit.info.unit_type == unit_type)
+            && self.magic.as_ref().map_or(true, |magic| {
+                unit.info
+                    .magic_type
+                    .is_some_and(|magic_type| magic_relates(magic_type, magic.clone()))
+            })
+            && match (self.field, owner_pos) {
+                (Some(field), Some(pos)) => field_type(pos.into(), *MAX_TROOPS) == field,
+                _ => true,
+            }
+    }
+}
+
+/// Bonus defined in Bonuses.ini, units and items refer to it by the section name
+#[derive(Clone, Debug)]
+pub struct CustomBonus {
+    pub name: String,
+    pub description: String,
+    pub hooks: Vec<BonusHook>,
+    pub condition: BonusCondition,
+    /// Stats change of the owner
+    pub modify: Option<ModifyUnitStats>,
+    /// Battle moves the stats change lasts, 0 for the whole battle
+    pub duration: i32,
+    /// Change of the damage in the attacks the bonus works on
+    pub damage: Percent,
+    /// Put on the other unit of an attack or a kill, on the owner otherwise
+    pub effect: Option<Effect>,
+    /// Effect kinds removed from the one the effect is put on
+    pub dispel: Vec<EffectKind>,
+}
+pub static CUSTOM_BONUSES: Lazy<Mutex<Vec<CustomBonus>>> = Lazy::new(|| Mutex::new(Vec::new()));
+
+pub fn custom_bonus_index(name: &str) -> Option<usize> {
+    CUSTOM_BONUSES
+        .lock()
+        .unwrap()
+        .iter()
+        .position(|bonus| bonus.name == name)
+}
+/// Copy of the bonus, the lock is not held while it is triggered
+pub fn get_custom_bonus(index: usize) -> Option<CustomBonus> {
+    CUSTOM_BONUSES.lock().unwrap().get(index).cloned()
+}
+
+impl CustomBonus {
+    pub fn works(&self, hook: BonusHook, unit: &Unit, owner_pos: Option<UnitPos>) -> bool {
+        self.hooks.contains(&hook) && self.condition.check(unit, owner_pos)
+    }
+    /// Applies the stats change to the owner, the dispel and the effect to the `other` unit, or to the owner if there is none
+    pub fn trigger(&self, owner: &mut Unit, other: Option<&mut Unit>) {
+        if let Some(modify) = self.modify {
+            owner.add_effect(BonusEffect {
+                info: EffectInfo {
+                    lifetime: if self.duration > 0 {
+                        self.duration
+                    } else {
+                        i32::MAX
+                    },
+                },
+                modify,
+            });
+        }
+        let target = other.unwrap_or(owner);
+        for kind in &self.dispel {
+            target.dispel(*kind);
+        }
+        if let Some(effect) = &self.effect {
+            target.add_effect(effect.clone());
+        }
+    }
+    pub fn correct_damage(&self, damage: Power) -> Power {
+        let percent = Percent::new(100 + self.damage.get());
+        Power {
+            hand: percent.calc(damage.hand),
+            ranged: percent.calc(damage.ranged),
+            magic: percent.calc(damage.magic),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        battle::{
+            battlefield::{skip_move, BattleInfo},
+            troop::Troop,
+        },
+        bonuses::Bonus,
+        parse::parse_bonuses,
+        rng::GameRng,
+        testing::{gen_army, get_unit},
+    };
+
+    #[test]
+    fn custom_bonuses() {
+        parse_bonuses(Some("dt/Bonuses.ini"));
+        assert!(matches!(Bonus::from("HolyWrath"), Bonus::Custom(_)));
+        assert!(matches!(Bonus::from("Unknown"), Bonus::NoBonus));
+        let battle = BattleInfo::default();
+        let damage = Power {
+            hand: 20,
+            ..Power::empty()
+        };
+        let mut attacker = get_unit(1, 10, 0);
+        attacker.bonus = Bonus::from("HolyWrath");
+        let mut target = get_unit(1, 10, 1);
+        let (pos, attacker_pos) = (UnitPos(0, 1), UnitPos(0, 1));
+        let hit = target.corrected_damage(&damage, &mut attacker, pos, attacker_pos, &battle);
+        assert_eq!(hit.hand, 20);
+        target.info.unit_type = UnitType::Undead;
+        let hit = target.corrected_damage(&damage, &mut attacker, pos, attacker_pos, &battle);
+        assert_eq!(hit.hand, 30);
+
+        let mut unit = get_unit(2, 10, 0);
+        unit.bonus = Bonus::from("Vigilance");
+        let mut troop = Troop::new(unit);
+        skip_move(&mut troop);
+        assert_eq!(troop.unit.modified.speed, 30);
+
+        let mut killer = get_unit(1, 10, 0);
+        killer.stats.damage.hand = 10;
+        killer.bonus = Bonus::from("Bloodlust");
+        killer.recalc();
+        let mut victim = get_unit(1, 10, 1);
+        let bonus = killer.get_bonus();
+        assert!(bonus.on_kill(&mut victim, &mut killer));
+        assert_eq!(killer.modified.damage.hand, 12);
+    }
+    #[test]
+    fn custom_bonus_ends_with_battle() {
+        parse_bonuses(Some("dt/Bonuses.ini"));
+        let mut rng = GameRng::new(0);
+        let mut armys = vec![gen_army(0, &mut rng), gen_army(1, &mut rng)];
+        armys[0].troops[0].get().unit.bonus = Bonus::from("Steadfast");
+        for _ in 0..2 {
+            let mut battle = BattleInfo::new(&mut armys, 0, 1, rng.fork());
+            assert_eq!(armys[0].troops[0].get().unit.modified.defence.hand_units, 5);
+            battle.end(&mut armys);
+            let unit = &armys[0].troops[0].get().unit;
+            assert_eq!(unit.modified.defence.hand_units, 0);
+            assert!(unit.effects.is_empty());
+        }
+    }
+}
@@ -1,2 +1,4 @@
 pub mod bonus;
+pub mod custom;
 pub use bonus::*;
+pub use custom::*;
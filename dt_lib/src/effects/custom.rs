@@ -0,0 +1,146 @@
+use crate::{
+    effects::effect::{Effect, EffectInfo, EffectKind, EffectTrait},
+    units::{unit::Unit, unitstats::ModifyUnitStats},
+};
+use alkahest::alkahest;
+use math_thingies::Percent;
+use once_cell::sync::Lazy;
+use tracing_mutex::stdsync::TracingMutex as Mutex;
+
+/// Effect made from a definition of Effects.ini
+#[derive(Copy, Clone, Debug)]
+#[alkahest(Deserialize, Serialize, SerializeRef, Formula)]
+pub struct CustomEffect {
+    /// Index of the definition in `EFFECTS`
+    pub id: usize,
+    pub info: EffectInfo,
+    pub kind: EffectKind,
+    /// Hits lost every battle move, negative heals
+    pub tick_hits: i64,
+    /// Percent of the max hits lost every battle move, negative heals
+    pub tick_percent: Percent,
+    pub modify: ModifyUnitStats,
+    pub ends_with_battle: bool,
+}
+impl EffectTrait for CustomEffect {
+    fn update_stats(&mut self, unit: &mut Unit) {
+        unit.modify += self.modify;
+    }
+    fn tick(&mut self, unit: &mut Unit) -> bool {
+        let hits = self.tick_hits + self.tick_percent.calc(unit.modified.max_hp);
+        if hits < 0 {
+            unit.heal(-hits as u64);
+        } else {
+            unit.stats.hp -= hits;
+        }
+        self.on_tick()
+    }
+    fn on_tick(&mut self) -> bool {
+        self.info.lifetime -= 1;
+        true
+    }
+    fn on_battle_end(&mut self) -> bool {
+        if self.ends_with_battle {
+            self.info.lifetime = 0;
+        }
+        self.ends_with_battle
+    }
+    fn kill(&mut self, unit: &mut Unit) {
+        unit.modify -= self.modify;
+    }
+    fn is_dead(&self) -> bool {
+        self.info.lifetime < 1
+    }
+    fn get_kind(&self) -> EffectKind {
+        self.kind
+    }
+    fn power(&self) -> i64 {
+        self.tick_hits + self.tick_percent.get() as i64
+    }
+}
+
+/// Definition of Effects.ini, bonuses, spells and potions refer to it by the section name
+#[derive(Clone, Debug)]
+pub struct EffectTemplate {
+    pub name: String,
+    pub effect: CustomEffect,
+}
+pub static EFFECTS: Lazy<Mutex<Vec<EffectTemplate>>> = Lazy::new(|| Mutex::new(Vec::new()));
+
+pub fn effect_index(name: &str) -> Option<usize> {
+    EFFECTS
+        .lock()
+        .unwrap()
+        .iter()
+        .position(|template| template.name == name)
+}
+/// New instance of the effect with the index
+pub fn custom_effect(index: usize) -> Option<Effect> {
+    EFFECTS
+        .lock()
+        .unwrap()
+        .get(index)
+        .map(|template| template.effect.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        parse::parse_effects,
+        testing::{gen_battle, get_unit},
+    };
+
+    #[test]
+    fn custom_effects() {
+        parse_effects(Some("dt/Effects.ini"));
+        let effect = |name| custom_effect(effect_index(name).unwrap()).unwrap();
+        let mut unit = get_unit(1, 10, 0);
+        unit.stats.hp = 50;
+        unit.recalc();
+        unit.add_effect(effect("Bleeding"));
+        unit.add_effect(effect("Stoneskin"));
+        assert!(unit.has_effect_kind(EffectKind::Poison));
+        assert!(unit.has_effect_kind(EffectKind::MageSupport));
+        assert_eq!(unit.modified.defence.hand_units, 10);
+
+        unit.tick();
+        assert_eq!(unit.modified.hp, 45);
+        assert_eq!(unit.modified.defence.hand_units, 10);
+        unit.tick();
+        assert_eq!(unit.modified.hp, 40);
+        assert_eq!(unit.modified.defence.hand_units, 0);
+        assert_eq!(unit.effects.len(), 1);
+
+        unit.add_effect(effect("Regeneration"));
+        unit.tick();
+        assert_eq!(unit.modified.hp, 50);
+    }
+    #[test]
+    fn custom_effects_end_with_battle() {
+        parse_effects(Some("dt/Effects.ini"));
+        let (mut armys, mut battle) = gen_battle(0);
+        let weakness = custom_effect(effect_index("Weakness").unwrap()).unwrap();
+        let Effect::CustomEffect(mut lasting) =
+            custom_effect(effect_index("Stoneskin").unwrap()).unwrap()
+        else {
+            panic!("Not a custom effect")
+        };
+        lasting.ends_with_battle = false;
+        {
+            let mut troop = armys[0].troops[0].get();
+            let unit = &mut troop.unit;
+            unit.stats.damage.hand = 50;
+            unit.add_effect(weakness);
+            unit.add_effect(lasting.into());
+            unit.recalc();
+            assert_eq!(unit.modified.damage.hand, 40);
+            assert_eq!(unit.modified.defence.hand_units, 10);
+        }
+        battle.end(&mut armys);
+        let unit = &armys[0].troops[0].get().unit;
+        assert_eq!(unit.modified.damage.hand, 50);
+        assert_eq!(unit.modified.defence.hand_units, 10);
+        assert_eq!(unit.effects.len(), 1);
+    }
+}
@@ -1,14 +1,17 @@
-use crate::units::{
-    unit::{Unit, *},
-    unitstats::*,
+use crate::{
+    effects::custom::CustomEffect,
+    units::{
+        unit::{Unit, *},
+        unitstats::*,
+    },
 };
 use alkahest::alkahest;
 use dyn_clone::DynClone;
 use enum_dispatch::enum_dispatch;
 use math_thingies::Percent;
-use std::fmt::Debug;
+use std::{fmt::Debug, str::FromStr};
 
-#[derive(PartialEq)]
+#[derive(Clone, Copy, Debug, PartialEq)]
 #[alkahest(Deserialize, Serialize, SerializeRef, Formula)]
 pub enum EffectKind {
     MageCurse,
@@ -19,6 +22,55 @@ pub enum EffectKind {
     Poison,
     Fire,
 }
+impl FromStr for EffectKind {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim() {
+            "MageCurse" => Ok(Self::MageCurse),
+            "MageSupport" => Ok(Self::MageSupport),
+            "Bonus" => Ok(Self::Bonus),
+            "Item" => Ok(Self::Item),
+            "Potion" => Ok(Self::Potion),
+            "Poison" => Ok(Self::Poison),
+            "Fire" => Ok(Self::Fire),
+            kind => Err(format!("Unknown effect kind {kind}")),
+        }
+    }
+}
+impl EffectKind {
+    pub fn stack_policy(&self) -> StackPolicy {
+        match self {
+            Self::MageCurse | Self::MageSupport => StackPolicy::Ignore,
+            Self::Poison => StackPolicy::Refresh,
+            Self::Fire => StackPolicy::ReplaceStronger,
+            Self::Bonus | Self::Item | Self::Potion => StackPolicy::Stack,
+        }
+    }
+    pub fn locale_id(&self) -> &'static str {
+        match self {
+            Self::MageCurse => "effectkind_magecurse",
+            Self::MageSupport => "effectkind_magesupport",
+            Self::Bonus => "effectkind_bonus",
+            Self::Item => "effectkind_item",
+            Self::Potion => "effectkind_potion",
+            Self::Poison => "effectkind_poison",
+            Self::Fire => "effectkind_fire",
+        }
+    }
+}
+
+/// What happens when a unit gets an effect of a kind it already has
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum StackPolicy {
+    /// The old effect lasts as long as the longer of the two
+    Refresh,
+    /// Both effects work
+    Stack,
+    /// The new effect is not applied
+    Ignore,
+    /// The new effect takes the place of the old one if it is more powerful
+    ReplaceStronger,
+}
 
 #[enum_dispatch]
 #[derive(Clone, Debug)]
@@ -36,6 +88,9 @@ pub enum Effect {
     SpearEffect(SpearEffect),
     ItemEffect(ItemEffect),
     ToEndEffect(ToEndEffect),
+    PotionEffect(PotionEffect),
+    BonusEffect(BonusEffect),
+    CustomEffect(CustomEffect),
 }
 
 dyn_clone::clone_trait_object!(EffectTrait);
@@ -59,6 +114,34 @@ pub trait EffectTrait: DynClone + Debug + Send + Sync {
     fn get_kind(&self) -> EffectKind {
         EffectKind::Bonus
     }
+    /// Compared when the effect replaces a weaker one
+    fn power(&self) -> i64 {
+        0
+    }
+    fn get_magic(&self) -> Option<MagicType> {
+        None
+    }
+}
+impl Effect {
+    pub fn info_mut(&mut self) -> Option<&mut EffectInfo> {
+        match self {
+            Self::MoreMoves(MoreMoves { info, .. })
+            | Self::HealMagic(HealMagic { info, .. })
+            | Self::DisableMagic(DisableMagic { info, .. })
+            | Self::ElementalSupport(ElementalSupport { info, .. })
+            | Self::AttackMagic(AttackMagic { info, .. })
+            | Self::Poison(Poison { info, .. })
+            | Self::Fire(Fire { info, .. })
+            | Self::ArtilleryEffect(ArtilleryEffect { info, .. })
+            | Self::SpearEffect(SpearEffect { info, .. })
+            | Self::ItemEffect(ItemEffect { info, .. })
+            | Self::ToEndEffect(ToEndEffect { info, .. })
+            | Self::PotionEffect(PotionEffect { info, .. })
+            | Self::BonusEffect(BonusEffect { info, .. })
+            | Self::CustomEffect(CustomEffect { info, .. }) => Some(info),
+            Self::RessurectedEffect(_) => None,
+        }
+    }
 }
 
 #[derive(PartialEq, Copy, Clone, Debug)]
@@ -102,6 +185,7 @@ impl EffectTrait for MoreMoves {
 pub struct HealMagic {
     pub info: EffectInfo,
     pub magic_power: u64,
+    pub magic_type: MagicType,
 }
 impl HealMagic {
     pub fn new(magic_power: u64) -> Self {
@@ -115,6 +199,7 @@ impl Default for HealMagic {
         Self {
             info: EffectInfo { lifetime: 1 },
             magic_power: 15,
+            magic_type: MagicType::Life(MagicDirection::ToAlly),
         }
     }
 }
@@ -163,6 +248,12 @@ impl EffectTrait for HealMagic {
     fn get_kind(&self) -> EffectKind {
         EffectKind::MageSupport
     }
+    fn power(&self) -> i64 {
+        self.magic_power as i64
+    }
+    fn get_magic(&self) -> Option<MagicType> {
+        Some(self.magic_type)
+    }
 }
 
 #[derive(Copy, Clone, Debug)]
@@ -217,6 +308,9 @@ impl EffectTrait for DisableMagic {
     fn get_kind(&self) -> EffectKind {
         EffectKind::MageCurse
     }
+    fn power(&self) -> i64 {
+        self.magic_power as i64
+    }
 }
 
 #[derive(Copy, Clone, Debug)]
@@ -271,6 +365,9 @@ impl EffectTrait for ElementalSupport {
     fn get_kind(&self) -> EffectKind {
         EffectKind::MageSupport
     }
+    fn power(&self) -> i64 {
+        self.magic_power as i64
+    }
 }
 
 #[derive(Copy, Clone, Debug)]
@@ -349,6 +446,12 @@ impl EffectTrait for AttackMagic {
     fn get_kind(&self) -> EffectKind {
         EffectKind::MageCurse
     }
+    fn power(&self) -> i64 {
+        self.magic_power as i64
+    }
+    fn get_magic(&self) -> Option<MagicType> {
+        Some(self.magic_type)
+    }
 }
 
 const POISON_PERCENT: Percent = Percent::const_new(15);
@@ -392,13 +495,12 @@ pub struct Fire {
 }
 impl EffectTrait for Fire {
     fn update_stats(&mut self, unit: &mut Unit) {
-        let mut unitstats = unit.stats;
-        unitstats.hp -= (FIRE_PERCENT + Percent::new(self.additional_power as i16 / 5))
-            .calc(unitstats.hp)
+        unit.stats.hp -= (FIRE_PERCENT + Percent::new(self.additional_power as i16 / 5))
+            .calc(unit.stats.hp)
             * ((unit.info.unit_type == UnitType::Mecha) as i64 + 1);
         self.addition_speed =
-            FIRE_SLOWNESS_PERCENT.calc(unitstats.speed) + self.additional_power / 10;
-        unitstats.speed -= self.addition_speed;
+            FIRE_SLOWNESS_PERCENT.calc(unit.stats.speed) + self.additional_power / 10;
+        unit.modify.speed -= *Modify::default().add(self.addition_speed);
     }
     fn on_tick(&mut self) -> bool {
         self.info.lifetime -= 1;
@@ -409,7 +511,7 @@ impl EffectTrait for Fire {
         true
     }
     fn kill(&mut self, unit: &mut Unit) {
-        unit.stats.speed = unit.stats.speed - self.addition_speed;
+        unit.modify.speed += *Modify::default().add(self.addition_speed);
     }
     fn is_dead(&self) -> bool {
         self.info.lifetime < 1
@@ -417,6 +519,9 @@ impl EffectTrait for Fire {
     fn get_kind(&self) -> EffectKind {
         EffectKind::Fire
     }
+    fn power(&self) -> i64 {
+        self.additional_power
+    }
 }
 const STANDART_FIRE_LONG: i32 = 5;
 impl Fire {
@@ -547,3 +652,121 @@ impl EffectTrait for ToEndEffect {
         EffectKind::Bonus
     }
 }
+
+/// Stats change from a potion, lasts some battle moves and ends with the battle
+#[derive(Copy, Clone, Debug)]
+#[alkahest(Deserialize, Serialize, SerializeRef, Formula)]
+pub struct PotionEffect {
+    pub info: EffectInfo,
+    pub modify: ModifyUnitStats,
+}
+impl EffectTrait for PotionEffect {
+    fn update_stats(&mut self, unit: &mut Unit) {
+        unit.modify += self.modify;
+    }
+    fn on_tick(&mut self) -> bool {
+        self.info.lifetime -= 1;
+        true
+    }
+    fn on_battle_end(&mut self) -> bool {
+        self.info.lifetime = 0;
+        true
+    }
+    fn kill(&mut self, unit: &mut Unit) {
+        unit.modify -= self.modify;
+    }
+    fn is_dead(&self) -> bool {
+        self.info.lifetime < 1
+    }
+    fn get_kind(&self) -> EffectKind {
+        EffectKind::Potion
+    }
+}
+
+/// Stats change from a custom bonus, lasts some battle moves and ends with the battle
+#[derive(Copy, Clone, Debug)]
+#[alkahest(Deserialize, Serialize, SerializeRef, Formula)]
+pub struct BonusEffect {
+    pub info: EffectInfo,
+    pub modify: ModifyUnitStats,
+}
+impl EffectTrait for BonusEffect {
+    fn update_stats(&mut self, unit: &mut Unit) {
+        unit.modify += self.modify;
+    }
+    fn on_tick(&mut self) -> bool {
+        self.info.lifetime -= 1;
+        true
+    }
+    fn on_battle_end(&mut self) -> bool {
+        self.info.lifetime = 0;
+        true
+    }
+    fn kill(&mut self, unit: &mut Unit) {
+        unit.modify -= self.modify;
+    }
+    fn is_dead(&self) -> bool {
+        self.info.lifetime < 1
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{bonuses::Bonus, parse::parse_bonuses, testing::get_unit};
+
+    #[test]
+    fn effect_stacking() {
+        let mut unit = get_unit(1, 10, 0);
+        assert!(unit.add_effect(HealMagic::new(20)));
+        assert!(!unit.add_effect(HealMagic::new(40)));
+        assert!(unit.add_effect(AttackMagic::new(20)));
+        assert_eq!(unit.effects.len(), 2);
+
+        unit.add_effect(Poison {
+            info: EffectInfo { lifetime: 2 },
+        });
+        assert!(unit.add_effect(Poison {
+            info: EffectInfo { lifetime: 5 },
+        }));
+        assert_eq!(unit.effects.len(), 3);
+        assert!(matches!(unit.effects[2], Effect::Poison(Poison { info }) if info.lifetime == 5));
+
+        assert!(unit.add_effect(Fire::new(10)));
+        assert!(!unit.add_effect(Fire::new(5)));
+        assert!(unit.add_effect(Fire::new(50)));
+        assert_eq!(unit.effects.len(), 4);
+        assert!(matches!(&unit.effects[3], Effect::Fire(fire) if fire.power() == 50));
+
+        assert_eq!(unit.dispel(EffectKind::Fire), 1);
+        assert!(!unit.has_effect_kind(EffectKind::Fire));
+
+        let mut mecha = get_unit(1, 10, 0);
+        mecha.info.unit_type = UnitType::Mecha;
+        assert!(!mecha.add_effect(Poison::default()));
+        let mut undead = get_unit(1, 10, 0);
+        undead.info.unit_type = UnitType::Undead;
+        assert!(!undead.add_effect(HealMagic::new(20)));
+        assert!(undead.add_effect(HealMagic {
+            magic_type: MagicType::Death(MagicDirection::ToAlly),
+            ..HealMagic::new(20)
+        }));
+
+        parse_bonuses(Some("dt/Bonuses.ini"));
+        unit.bonus = Bonus::from("Purity");
+        unit.stats.hp = 50;
+        unit.tick();
+        assert!(!unit.has_effect_kind(EffectKind::Poison));
+        assert!(!unit.has_effect_kind(EffectKind::MageSupport));
+    }
+    #[test]
+    fn effect_takes_back_its_changes() {
+        let mut unit = get_unit(1, 100, 0);
+        assert!(unit.add_effect(Fire::new(0)));
+        assert_eq!(unit.modified.hp, 90);
+        assert_eq!(unit.modified.speed, 90);
+        assert_eq!(unit.dispel(EffectKind::Fire), 1);
+        assert_eq!(unit.modified.speed, 100);
+        assert_eq!(unit.stats.speed, 100);
+    }
+}
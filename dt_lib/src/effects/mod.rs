@@ -1,2 +1,4 @@
+pub mod custom;
 pub mod effect;
+pub use custom::*;
 pub use effect::*;
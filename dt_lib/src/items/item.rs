@@ -2,7 +2,11 @@ use crate::units::unitstats::ModifyUnitStats;
 
 use crate::{
     bonuses::bonus::Bonus,
-    units::unit::{MagicType, Unit},
+    effects::{
+        custom::custom_effect,
+        effect::{EffectInfo, PotionEffect},
+    },
+    units::unit::{MagicType, Unit, UnitInventory},
 };
 use advini::{Ini, IniParseError};
 use alkahest::alkahest;
@@ -12,7 +16,17 @@ use tracing_mutex::stdsync::TracingMutex as Mutex;
 #[derive(Debug, Clone, PartialEq)]
 pub enum ItemType {
     Artifact,
-    Potion,
+    Potion(Potion),
+}
+/// One-shot payload of a consumable, the `modify` of the item is applied along with it
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct Potion {
+    /// Hits restored
+    pub heal: u64,
+    /// Battle moves the stats change lasts, 0 changes the stats for good
+    pub duration: i32,
+    /// Index in `EFFECTS` of the effect the potion puts on
+    pub effect: Option<usize>,
 }
 #[derive(Debug, Clone, PartialEq)]
 pub enum ArtifactType {
@@ -38,10 +52,38 @@ pub struct ItemInfo {
     pub icon: String,
     pub sells: bool,
     pub itemtype: ArtifactType,
+    pub kind: ItemType,
     pub bonus: Option<Bonus>,
     pub modify: ModifyUnitStats,
 }
 pub static ITEMS: Lazy<Mutex<HashMap<usize, ItemInfo>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+/// Themed items giving more when several of them are equipped on one unit
+#[derive(Clone, Debug)]
+pub struct ItemSet {
+    pub name: String,
+    /// Global indexes of the items of the set
+    pub items: Vec<usize>,
+    /// Equipped items of the set needed for it to work
+    pub pieces: usize,
+    pub bonus: Option<Bonus>,
+    pub modify: ModifyUnitStats,
+}
+impl ItemSet {
+    pub fn works(&self, inventory: &UnitInventory) -> bool {
+        let equipped = self
+            .items
+            .iter()
+            .filter(|index| {
+                inventory
+                    .items
+                    .iter()
+                    .any(|item| item.is_some_and(|item| item.index == **index))
+            })
+            .count();
+        equipped >= self.pieces.max(1)
+    }
+}
+pub static ITEM_SETS: Lazy<Mutex<Vec<ItemSet>>> = Lazy::new(|| Mutex::new(Vec::new()));
 #[derive(Clone, Copy, Debug)]
 #[alkahest(Deserialize, Serialize, SerializeRef, Formula)]
 pub struct Item {
@@ -62,6 +104,37 @@ impl Item {
     pub fn get_info(&self) -> ItemInfo {
         ITEMS.lock().unwrap().get(&self.index).unwrap().clone()
     }
+    pub fn is_potion(&self) -> bool {
+        matches!(self.get_info().kind, ItemType::Potion(_))
+    }
+    /// Uses the potion on the unit, false if the item is not a potion or the unit is dead
+    pub fn drink(&self, unit: &mut Unit) -> bool {
+        let info = self.get_info();
+        let ItemType::Potion(potion) = info.kind else {
+            return false;
+        };
+        if unit.is_dead() {
+            return false;
+        }
+        if potion.duration > 0 {
+            unit.add_effect(PotionEffect {
+                info: EffectInfo {
+                    lifetime: potion.duration,
+                },
+                modify: info.modify,
+            });
+        } else {
+            unit.stats = info.modify.apply(&unit.stats);
+            unit.recalc();
+        }
+        if let Some(effect) = potion.effect.and_then(custom_effect) {
+            unit.add_effect(effect);
+        }
+        if potion.heal > 0 {
+            unit.heal(potion.heal);
+        }
+        true
+    }
     pub fn can_equip(&self, unit: &Unit) -> bool {
         let info = self.get_info();
         match info.itemtype {
@@ -123,3 +196,98 @@ pub fn magic_relates(magic_type: MagicType, magic_variant: MagicVariants) -> boo
         (_, _) => false,
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        battle::{
+            army::MAX_TROOPS,
+            battlefield::{field_type, handle_action, Action, BattleInfo, Field},
+            troop::Troop,
+        },
+        parse::parse_items,
+        rng::GameRng,
+        testing::{gen_army, get_unit},
+        units::unit::{ActionResult, Power, UnitPos},
+    };
+
+    #[test]
+    fn item_sets() {
+        let _ = parse_items(Some("dt/Rus_Artefacts.ini"), &"Rus".into());
+        let viking = ITEM_SETS
+            .lock()
+            .unwrap()
+            .iter()
+            .position(|set| set.items == vec![19, 48, 50])
+            .unwrap();
+        let mut unit = get_unit(1, 10, 0);
+        unit.stats.damage.hand = 10;
+        unit.inventory.items = vec![None; 4];
+        unit.recalc();
+        assert!(unit.add_item(Item { index: 48 }, 0));
+        assert!(unit.add_item(Item { index: 50 }, 1));
+        assert!(unit.inventory.sets.is_empty());
+        assert_eq!(unit.modified.defence.hand_units, 6);
+        assert!(unit.add_item(Item { index: 19 }, 2));
+        assert_eq!(unit.inventory.sets, vec![viking]);
+        assert_eq!(unit.modified.defence.hand_units, 11);
+        assert_eq!(unit.modified.damage.hand, 25);
+        assert!(matches!(unit.bonuses()[..], [Bonus::NoBonus, Bonus::Berserk]));
+
+        unit.remove_item(0);
+        assert!(unit.inventory.sets.is_empty());
+        assert_eq!(unit.modified.defence.hand_units, 3);
+        assert_eq!(unit.modified.damage.hand, 15);
+        assert!(matches!(unit.bonuses()[..], [Bonus::NoBonus]));
+    }
+    #[test]
+    fn set_bonus_with_native_bonus() {
+        let _ = parse_items(Some("dt/Rus_Artefacts.ini"), &"Rus".into());
+        let mut unit = get_unit(1, 100, 0);
+        unit.bonus = Bonus::GodAnger;
+        unit.stats.damage.hand = 10;
+        unit.inventory.items = vec![None; 4];
+        for (slot, item) in [48, 50, 19].into_iter().enumerate() {
+            assert!(unit.add_item(Item { index: item }, slot));
+        }
+        assert!(matches!(unit.bonuses()[..], [Bonus::GodAnger, Bonus::Berserk]));
+        let hand = unit.modified.damage.hand;
+
+        let battle = BattleInfo::default();
+        let damage = Power {
+            hand: 20,
+            ..Power::empty()
+        };
+        let pos = UnitPos(0, 1);
+        let hit = get_unit(1, 10, 1).corrected_damage(&damage, &mut unit, pos, pos, &battle);
+        assert_eq!(hit.hand, 30);
+
+        let mut rng = GameRng::new(0);
+        let mut attacker = gen_army(0, &mut rng);
+        attacker.troops.clear();
+        attacker.add_troop(Troop::new(unit).into()).ok();
+        attacker.troops[0].get().pos = pos;
+        attacker.recalc_army_hitmap();
+        let mut defender = gen_army(1, &mut rng);
+        for troop in &defender.troops {
+            let mut troop = troop.get();
+            troop.unit.stats.hp = 1;
+            troop.unit.recalc();
+        }
+        defender.recalc_army_hitmap();
+        let mut armys = vec![attacker, defender];
+        let mut battle = BattleInfo::new(&mut armys, 0, 1, rng.fork());
+        let cell = (0..*MAX_TROOPS)
+            .find(|cell| {
+                UnitPos::from_index(*cell).1 == 1
+                    && armys[1].hitmap[*cell].is_some()
+                    && field_type(*cell, *MAX_TROOPS) == Field::Front
+            })
+            .unwrap();
+        let (res, _) = handle_action(Action::Cell(cell, 1), &mut battle, &mut armys);
+        assert_eq!(res.map(|res| res.0), Some(ActionResult::Melee));
+        // Berserk of the set works next to the native bonus after the kill
+        assert!(armys[0].troops[0].get().unit.modified.damage.hand > hand);
+    }
+}
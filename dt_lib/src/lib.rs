@@ -7,6 +7,10 @@ pub mod map;
 pub mod mutrc;
 pub mod network;
 pub mod parse;
+pub mod rng;
+pub mod spells;
+#[cfg(test)]
+mod testing;
 pub mod time;
 pub mod units;
 
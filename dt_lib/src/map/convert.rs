@@ -132,6 +132,14 @@ pub struct ArmyData {
 	pub action_model: u8, // модель поведения 86
 	pub _empty6: [u8; 3], // 3 пустых байта 89 
 }
+impl ArmyData {
+	/// Spellbook of the army, the applied spell of the map if it is known
+	pub fn spells(&self) -> Vec<usize> {
+		crate::spells::spell_by_id(self.applied_spell)
+			.into_iter()
+			.collect()
+	}
+}
 #[derive(Debug, Clone, Copy, FromBytes)]
 pub struct LightOrEvent {
 	pub x: u16,
@@ -1,5 +1,5 @@
 use crate::{
-    battle::troop::Troop,
+    battle::troop::{Troop, TroopBattleStats},
     map::map::GameMap,
     mutrc::SendMut,
     time::time::Time,
@@ -504,6 +504,7 @@ pub fn execute_event_as_player(
                     army.add_troop(SendMut::new(Troop {
                         unit: units[*unit].clone(),
                         custom_name: None,
+                        battle_stats: TroopBattleStats::default(),
                         is_free: true,
                         was_payed: true,
                         is_main: false,
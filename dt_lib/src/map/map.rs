@@ -1,11 +1,18 @@
 use super::{
     object::{MapBuildingdata, ObjectInfo},
+    terrain::BattleTerrain,
     tile::*,
 };
 use crate::{
-    battle::army::Army,
+    battle::{
+        ai::auto_resolve,
+        army::{Army, TroopType},
+        battlefield::{BattleAwards, BattleInfo, BattlePlace, BattleRules, Siege},
+    },
+    rng::GameRng,
+    spells::{get_spell, SpellTarget},
     time::time::Time,
-	battle::control::Relations
+	battle::control::{Fraction, Relations}
 };
 use advini::{Ini, IniParseError, Section, SectionError, Sections};
 use alkahest::alkahest;
@@ -35,16 +42,25 @@ impl Default for HitboxTile {
     }
 }
 
-#[derive(Clone, Debug, Default, Sections)]
+#[derive(Clone, Debug, Sections)]
 #[alkahest(Deserialize, Serialize, SerializeRef, Formula)]
 pub struct FractionsRelations {
     #[default_value = "Relations::default()"]
     pub ally: Relations,
-    #[default_value = "Relations::default()"]
+    #[default_value = "Relations::new(128, 128, 0, 128)"]
     pub neighbour: Relations,
-    #[default_value = "Relations::default()"]
+    #[default_value = "Relations::new(255, 255, 128, 0)"]
     pub enemy: Relations,
 }
+impl Default for FractionsRelations {
+    fn default() -> Self {
+        Self::new(
+            Relations::default(),
+            Relations::new(128, 128, 0, 128),
+            Relations::new(255, 255, 128, 0),
+        )
+    }
+}
 impl FractionsRelations {
     fn new(ally: Relations, neighbour: Relations, enemy: Relations) -> Self {
         Self {
@@ -53,7 +69,20 @@ impl FractionsRelations {
             enemy,
         }
     }
+    /// How hostile armies of one fraction are to another, player armies are seen the way the others see them
+    pub fn hostility(&self, of: Fraction, to: Fraction) -> u8 {
+        let relations = match of {
+            Fraction::Player if to == Fraction::Player => return 0,
+            Fraction::Player => return self.hostility(to, of),
+            Fraction::Ally => &self.ally,
+            Fraction::Neighbour => &self.neighbour,
+            Fraction::Enemy => &self.enemy,
+        };
+        relations.to(to)
+    }
 }
+/// Armies at most this hostile to a fraction help it in battles on adjacent tiles
+const MAX_ALLY_HOSTILITY: u8 = 64;
 pub const MAP_SIZE: usize = 50;
 
 #[derive(Clone, Debug, Default, Sections)]
@@ -92,6 +121,10 @@ pub struct GameMap {
     pub relations: FractionsRelations,
     #[unused]
     pub pause: bool,
+    #[default_value = "GameRng::default()"]
+    pub rng: GameRng,
+    #[unused]
+    pub battle_rules: BattleRules,
 }
 impl Default for GameMap {
     fn default() -> Self {
@@ -105,19 +138,201 @@ impl Default for GameMap {
             armys: Vec::new(),
             relations: Default::default(),
             pause: false,
+            rng: GameRng::default(),
+            battle_rules: BattleRules::default(),
         }
     }
 }
 impl GameMap {
-    pub fn new(start: StartStats, relations: FractionsRelations) -> Self {
+    pub fn new(start: StartStats, relations: FractionsRelations, rng: GameRng) -> Self {
         let time = start.time;
         Self {
             start,
             relations,
             time,
+            rng,
             ..Default::default()
         }
     }
+    /// Armies next to the fighting ones that are friendly to one side and hostile to the other, as (army, side)
+    pub fn battle_allies(&self, army1: usize, army2: usize) -> Vec<(usize, usize)> {
+        let near = |army: &Army| {
+            [army1, army2].into_iter().any(|fighting| {
+                let pos = self.armys[fighting].pos;
+                army.pos.0.abs_diff(pos.0) <= 1 && army.pos.1.abs_diff(pos.1) <= 1
+            })
+        };
+        let fractions = [self.armys[army1].fraction, self.armys[army2].fraction];
+        self.armys
+            .iter()
+            .enumerate()
+            .filter(|(index, army)| {
+                *index != army1 && *index != army2 && army.active && !army.defeated && near(army)
+            })
+            .filter_map(|(index, army)| {
+                let friendly = fractions.map(|fraction| {
+                    self.relations.hostility(army.fraction, fraction) <= MAX_ALLY_HOSTILITY
+                });
+                match friendly {
+                    [true, false] => Some((index, 0)),
+                    [false, true] => Some((index, 1)),
+                    _ => None,
+                }
+            })
+            .collect()
+    }
+    /// Starts a battle with the rules of the map on the tile of the defender, army1 is the defender and army2 is the attacker.
+    /// A defender inside a building is besieged: it gets the defense of the building and the help of its garrison.
+    /// Allies standing next to the battle lend their troops to the side they help while there is place for them.
+    pub fn start_battle(&mut self, army1: usize, army2: usize) -> BattleInfo {
+        let siege = self.armys[army1].building.map(|building| Siege {
+            building,
+            defense: self.buildings[building].defense,
+        });
+        let mut garrison = Vec::new();
+        if let Some(siege) = siege {
+            let building = &mut self.buildings[siege.building];
+            // Garrison doesn't help the ones who don't own the building
+            if building.owner.map_or(true, |owner| owner == army1) {
+                let army = &mut self.armys[army1];
+                building.garrison.retain(|troop| {
+                    troop.get().unit.army = army1;
+                    if army.add_troop(troop.clone()).is_ok() {
+                        garrison.push(troop.clone());
+                        false
+                    } else {
+                        true
+                    }
+                });
+            }
+        }
+        let mut allies = Vec::new();
+        let mut lent = Vec::new();
+        for (ally, side) in self.battle_allies(army1, army2) {
+            let leader = if side == 0 { army1 } else { army2 };
+            let mut troops = std::mem::take(&mut self.armys[ally].troops);
+            let lent_before = lent.len();
+            troops.retain(|troop| {
+                troop.get().unit.army = leader;
+                if self.armys[leader].add_troop(troop.clone()).is_ok() {
+                    lent.push((ally, troop.clone()));
+                    false
+                } else {
+                    troop.get().unit.army = ally;
+                    true
+                }
+            });
+            let army = &mut self.armys[ally];
+            army.troops = troops;
+            army.recalc_army_hitmap();
+            if lent.len() > lent_before {
+                allies.push((ally, side));
+            }
+        }
+        let place = BattlePlace {
+            terrain: Some(BattleTerrain::new(self, self.armys[army1].pos)),
+            siege,
+        };
+        let mut battle = BattleInfo::with_place(
+            &mut self.armys,
+            army1,
+            army2,
+            self.rng.fork(),
+            self.battle_rules,
+            place,
+        );
+        battle.garrison = garrison;
+        battle.allies = allies;
+        battle.lent = lent;
+        battle
+    }
+    /// Fights the battle without the players and applies its result to the map.
+    /// The battle is returned for the prisoners, the winner still decides about them.
+    pub fn auto_battle(&mut self, army1: usize, army2: usize) -> (BattleInfo, BattleAwards) {
+        let mut battle = self.start_battle(army1, army2);
+        let awards = auto_resolve(&mut battle, &mut self.armys);
+        self.finish_battle(&mut battle);
+        (battle, awards)
+    }
+    /// Applies the result of a battle started with `start_battle` to the map: the battle ends if it wasn't ended yet,
+    /// allies take back their troops, surviving garrison returns to the building if it was held,
+    /// a building taken by the attacker gets a new owner
+    pub fn finish_battle(&mut self, battle: &mut BattleInfo) -> BattleAwards {
+        fn take_troop(troops: &mut Vec<TroopType>, troop: &TroopType) -> bool {
+            let len = troops.len();
+            troops.retain(|other| !other.ptr_eq(troop));
+            troops.len() != len
+        }
+        let awards = battle.end(&mut self.armys);
+        // Captured troops stay with the prisoners
+        for (ally, troop) in std::mem::take(&mut battle.lent) {
+            let side = battle.side(ally).expect("Lent troops come from the allies of the battle");
+            let leader = battle.side_armies(side)[0];
+            if take_troop(&mut self.armys[leader].troops, &troop)
+                || take_troop(&mut battle.dead, &troop)
+            {
+                troop.get().unit.army = ally;
+                self.armys[ally].add_troop(troop).ok();
+            }
+        }
+        self.armys[battle.army1].recalc_army_hitmap();
+        self.armys[battle.army2].recalc_army_hitmap();
+
+        let Some(siege) = battle.place.siege.take() else {
+            return awards;
+        };
+        let garrison = std::mem::take(&mut battle.garrison);
+        let in_garrison = |troop: &TroopType| garrison.iter().any(|other| other.ptr_eq(troop));
+        let army = &mut self.armys[battle.army1];
+        army.troops.retain(|troop| !in_garrison(troop));
+        army.recalc_army_hitmap();
+        battle.dead.retain(|troop| !in_garrison(troop));
+
+        let building = &mut self.buildings[siege.building];
+        if battle.winner == Some(battle.army2) {
+            building.owner = Some(battle.army2);
+        } else {
+            building
+                .garrison
+                .extend(garrison.into_iter().filter(|troop| !troop.get().is_dead()));
+        }
+        awards
+    }
+    /// Casts a spell of the army outside of a battle: `Army` spells work on the army itself,
+    /// `Tile` spells on a hostile army standing on the `tile` within the range of the spell
+    pub fn cast_spell(&mut self, army: usize, spell: usize, tile: (usize, usize)) -> bool {
+        let Some(info) = get_spell(spell) else {
+            return false;
+        };
+        if !info.on_map() {
+            return false;
+        }
+        let target = match info.target {
+            SpellTarget::Army => army,
+            SpellTarget::Tile => {
+                let pos = self.armys[army].pos;
+                if pos.0.abs_diff(tile.0) > info.range || pos.1.abs_diff(tile.1) > info.range {
+                    return false;
+                }
+                let hitbox = self.hitmap.get(tile.0).and_then(|line| line.get(tile.1));
+                match hitbox.and_then(|hitbox| hitbox.army) {
+                    Some(target)
+                        if target != army
+                            && self.relations.hostility(
+                                self.armys[army].fraction,
+                                self.armys[target].fraction,
+                            ) > MAX_ALLY_HOSTILITY =>
+                    {
+                        target
+                    }
+                    _ => return false,
+                }
+            }
+            SpellTarget::Ally | SpellTarget::Enemy => return false,
+        };
+        let troops = self.armys[target].troops.clone();
+        self.armys[army].cast_spell(spell, &troops)
+    }
     pub fn calc_hitboxes(&mut self, objects: &[ObjectInfo]) {
         for ((tileline, decoline), x) in self
             .tilemap
@@ -142,6 +357,21 @@ impl GameMap {
             }
         }
     }
+    /// Advances the map clock by one step, markets restock at the start of every day
+    pub fn pass_time(&mut self) {
+        let day = self.time.get_days();
+        self.time.minutes += 10;
+        if self.time.get_days() != day {
+            self.restock_markets();
+        }
+    }
+    pub fn restock_markets(&mut self) {
+        for building in &mut self.buildings {
+            if let Some(market) = &mut building.market {
+                market.update(&mut self.rng);
+            }
+        }
+    }
     pub fn recalc_armies_hitboxes(&mut self) {
         self.hitmap.iter_mut().for_each(|arr| {
             arr.iter_mut().for_each(|el| {
@@ -157,3 +387,67 @@ impl GameMap {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{map::object::Market, parse::parse_items};
+
+    fn market_map(seed: u64) -> GameMap {
+        let mut gamemap = GameMap::default();
+        gamemap.rng = GameRng::new(seed);
+        gamemap.buildings.push(MapBuildingdata {
+            name: String::new(),
+            desc: String::new(),
+            id: 0,
+            event: Vec::new(),
+            market: Some(Market {
+                itemcost_range: (0, u64::MAX),
+                items: Vec::new(),
+                max_items: 5,
+            }),
+            recruitment: None,
+            pos: (0, 0),
+            defense: 0,
+            income: 0,
+            owner: None,
+            garrison: Vec::new(),
+        });
+        gamemap
+    }
+    fn market_items(gamemap: &GameMap) -> Vec<usize> {
+        gamemap.buildings[0].market.as_ref().unwrap().items.clone()
+    }
+    #[test]
+    fn markets_restock_from_map_rng() {
+        let _ = parse_items(Some("dt/Rus_Artefacts.ini"), &"Rus".into());
+        let mut first = market_map(7);
+        let mut second = market_map(7);
+        first.restock_markets();
+        second.restock_markets();
+        assert_eq!(market_items(&first).len(), 5);
+        assert_eq!(market_items(&first), market_items(&second));
+        first.restock_markets();
+        assert_eq!(market_items(&first).len(), 5);
+    }
+    #[test]
+    fn markets_restock_on_new_day() {
+        let _ = parse_items(Some("dt/Rus_Artefacts.ini"), &"Rus".into());
+        let mut gamemap = market_map(7);
+        gamemap.time.minutes = 24 * 60 - 20;
+        gamemap.pass_time();
+        assert!(market_items(&gamemap).is_empty());
+        gamemap.pass_time();
+        assert_eq!(market_items(&gamemap).len(), 5);
+    }
+    #[test]
+    fn map_keeps_rng() {
+        let mut gamemap = GameMap::default();
+        gamemap.rng = GameRng::new(42);
+        gamemap.rng.fork();
+        let (loaded, _) = GameMap::from_section(gamemap.to_section()).unwrap();
+        assert_eq!(loaded.rng, gamemap.rng);
+        let mut rng = loaded.rng;
+        assert_eq!(rng.fork(), gamemap.rng.fork());
+    }
+}
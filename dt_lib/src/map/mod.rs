@@ -3,4 +3,5 @@ pub mod deco;
 pub mod event;
 pub mod map;
 pub mod object;
+pub mod terrain;
 pub mod tile;
@@ -1,12 +1,16 @@
 use std::collections::HashMap;
 
 use crate::{
-    battle::{army::Army, troop::Troop},
+    battle::{
+        army::{Army, TroopType},
+        troop::Troop,
+    },
     items::item::ITEMS,
+    rng::GameRng,
     units::unit::Unit,
 };
 use alkahest::alkahest;
-use rand::{seq::SliceRandom, thread_rng};
+use rand::seq::SliceRandom;
 
 #[derive(Clone, Copy, PartialEq, Eq, Debug)]
 pub enum ObjectType {
@@ -44,6 +48,8 @@ pub struct MapBuildingdata {
     pub defense: u64,
     pub income: u64,
     pub owner: Option<usize>,
+    /// Troops guarding the building, they join the owner when it is attacked inside
+    pub garrison: Vec<TroopType>,
 }
 
 const RECRUIT_COST: f64 = 2.0;
@@ -55,22 +61,19 @@ pub struct Market {
     pub max_items: usize,
 }
 impl Market {
-    fn update(&mut self) {
-        for _ in self.max_items - self.items.len()..0 {
-            let items = ITEMS.lock().unwrap();
-            let nice_items = items
-                .iter()
-                .filter(|(_, item)| {
-                    self.itemcost_range.0 <= item.cost && item.cost <= self.itemcost_range.1
-                })
-                .collect::<Vec<_>>();
-            self.items.append(
-                &mut nice_items
-                    .choose_multiple(&mut thread_rng(), self.max_items)
-                    .map(|(index, _)| **index)
-                    .collect(),
-            );
-        }
+    pub(crate) fn update(&mut self, rng: &mut GameRng) {
+        let missing = self.max_items.saturating_sub(self.items.len());
+        let items = ITEMS.lock().unwrap();
+        let mut nice_items = items
+            .iter()
+            .filter(|(_, item)| {
+                self.itemcost_range.0 <= item.cost && item.cost <= self.itemcost_range.1
+            })
+            .map(|(index, _)| *index)
+            .collect::<Vec<_>>();
+        // HashMap order differs between runs, the choice has to depend on the rng only
+        nice_items.sort();
+        self.items.extend(nice_items.choose_multiple(rng, missing).copied());
     }
     fn buy(&mut self, buyer: &mut Army, item_num: usize) {
         if self.can_buy(buyer, item_num) {
@@ -0,0 +1,58 @@
+use super::map::GameMap;
+use crate::{
+    effects::effect::{EffectInfo, ToEndEffect},
+    units::{unit::UnitType, unitstats::ModifyUnitStats},
+};
+use alkahest::alkahest;
+use once_cell::sync::Lazy;
+use tracing_mutex::stdsync::TracingMutex as Mutex;
+
+/// Change of the unit stats in battles on some terrain, one section of Terrain.ini
+#[derive(Clone, Debug)]
+pub struct TerrainModifier {
+    pub name: String,
+    /// Indexes in `TILES` the modifier works on, any tile if empty
+    pub tiles: Vec<usize>,
+    /// Works only if there is a deco object on the tile, e.g. a forest
+    pub deco: bool,
+    /// Works only on units of this type
+    pub unit_type: Option<UnitType>,
+    pub modify: ModifyUnitStats,
+}
+impl TerrainModifier {
+    fn works(&self, terrain: &BattleTerrain, unit_type: UnitType) -> bool {
+        (self.tiles.is_empty() || self.tiles.contains(&terrain.tile))
+            && (!self.deco || terrain.deco)
+            && self.unit_type.map_or(true, |needed| needed == unit_type)
+    }
+}
+pub static TERRAIN: Lazy<Mutex<Vec<TerrainModifier>>> = Lazy::new(|| Mutex::new(Vec::new()));
+
+/// Map tile where the armies of a battle met
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[alkahest(Deserialize, Serialize, SerializeRef, Formula)]
+pub struct BattleTerrain {
+    pub tile: usize,
+    pub deco: bool,
+}
+impl BattleTerrain {
+    pub fn new(gamemap: &GameMap, pos: (usize, usize)) -> Self {
+        Self {
+            tile: gamemap.tilemap[pos.0][pos.1],
+            deco: gamemap.decomap[pos.0][pos.1].is_some(),
+        }
+    }
+    /// Effects a unit of the type gets for the battle
+    pub fn effects(&self, unit_type: UnitType) -> Vec<ToEndEffect> {
+        TERRAIN
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|modifier| modifier.works(self, unit_type))
+            .map(|modifier| ToEndEffect {
+                info: EffectInfo { lifetime: i32::MAX },
+                modify: modifier.modify,
+            })
+            .collect()
+    }
+}
@@ -97,6 +97,10 @@ where
     pub fn clone(&self) -> Self {
         self.inner.clone().into()
     }
+    /// Both handles point to the same value
+    pub fn ptr_eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.inner, &other.inner)
+    }
 }
 impl<T> Clone for SendMut<T>
 where
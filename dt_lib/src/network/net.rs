@@ -33,7 +33,7 @@ use std::{
 #[derive(Clone, Debug)]
 #[alkahest(Deserialize, Serialize, SerializeRef, Formula)]
 pub enum ClientMessage {
-    Action((usize, usize)),
+    Action(Action),
     MapClick((usize, usize)),
 }
 
@@ -182,12 +182,12 @@ impl GameServer {
                 if Some(client_id.and_then(|v| self.auth.get(&v)).unwrap_or(&0usize))
                     == battle.active_unit.and_then(|v| Some(v.0)).as_ref()
                 {
-                    handle_action(
-                        Action::Cell(v.0 as usize, v.1 as usize),
-                        battle,
-                        &mut gamemap.armys,
-                    );
-                    let message = ServerMessage::State((Some(battle.clone()), gamemap.clone()));
+                    handle_action(v, battle, &mut gamemap.armys);
+                    if battle.is_over() {
+                        gamemap.finish_battle(battle);
+                    }
+                    let message =
+                        ServerMessage::State((Some(battle.for_clients()), gamemap.clone()));
                     let size = serialized_size::<ServerMessage, _>(&message);
                     let mut output = vec![0u8; size.0];
                     serialize::<ServerMessage, ServerMessage>(message, &mut output).ok();
@@ -219,8 +219,7 @@ impl GameServer {
                     let diff = (pos.0 as i64 - goal.0 as i64, pos.1 as i64 - goal.1 as i64);
                     if -1 <= diff.0 && diff.0 <= 1 && -1 <= diff.1 && diff.1 <= 1 {
                         if battle.is_none() {
-                            let battle_new =
-                                BattleInfo::new(&mut gamemap.armys, target_army, army_index);
+                            let battle_new = gamemap.start_battle(target_army, army_index);
                             *battle = Some(battle_new);
                         }
                         let message = ServerMessage::ChangeMenu(Menu::Connect as usize);
@@ -270,7 +269,8 @@ impl GameServer {
                         self.auth.insert(client_id, 1);
                     }
                     log::info!("Constructing server-client cross state");
-                    let message = ServerMessage::State((battle.clone(), gamemap.clone()));
+                    let state = battle.as_mut().map(BattleInfo::for_clients);
+                    let message = ServerMessage::State((state, gamemap.clone()));
                     log::info!("Calculate message size");
                     let size = serialized_size::<ServerMessage, _>(&message);
                     log::info!("Message serialized");
@@ -316,13 +316,14 @@ impl GameServer {
                             if self.auth.get(&client_id)
                                 == battle.active_unit.and_then(|v| Some(v.0)).as_ref()
                             {
-                                handle_action(
-                                    Action::Cell(v.0 as usize, v.1 as usize),
-                                    battle,
-                                    &mut gamemap.armys,
-                                );
-                                let message =
-                                    ServerMessage::State((Some(battle.clone()), gamemap.clone()));
+                                handle_action(v, battle, &mut gamemap.armys);
+                                if battle.is_over() {
+                                    gamemap.finish_battle(battle);
+                                }
+                                let message = ServerMessage::State((
+                                    Some(battle.for_clients()),
+                                    gamemap.clone(),
+                                ));
                                 let size = serialized_size::<ServerMessage, _>(&message);
                                 let mut output = vec![0u8; size.0];
                                 serialize::<ServerMessage, ServerMessage>(message, &mut output)
@@ -349,11 +350,8 @@ impl GameServer {
                                     (pos.0 as i64 - goal.0 as i64, pos.1 as i64 - goal.1 as i64);
                                 if -1 <= diff.0 && diff.0 <= 1 && -1 <= diff.1 && diff.1 <= 1 {
                                     if battle.is_none() {
-                                        let battle_new = BattleInfo::new(
-                                            &mut gamemap.armys,
-                                            target_army,
-                                            army_index,
-                                        );
+                                        let battle_new =
+                                            gamemap.start_battle(target_army, army_index);
                                         *battle = Some(battle_new);
                                     }
                                     let message = ServerMessage::ChangeMenu(Menu::Connect as usize);
@@ -379,7 +377,10 @@ impl GameServer {
                                 self.try_to_send_message(
                                     gamemap,
                                     army_index,
-                                    ServerMessage::State((battle.clone(), gamemap.clone())),
+                                    ServerMessage::State((
+                                        battle.as_mut().map(BattleInfo::for_clients),
+                                        gamemap.clone(),
+                                    )),
                                 );
                             };
                         }
@@ -415,7 +416,7 @@ impl GameServer {
                 }
                 gamemap.recalc_armies_hitboxes();
             }
-            gamemap.time.minutes += 10;
+            gamemap.pass_time();
 
             for i in 0..gameevents.len() {
                 if let Some(executions) = execute_event(i, gamemap, gameevents, units, false) {
@@ -427,7 +428,7 @@ impl GameServer {
                             }
                             Execute::StartBattle(army, player) => {
                                 if battle.is_none() {
-                                    let battle_new = BattleInfo::new(&mut gamemap.armys, army, 0);
+                                    let battle_new = gamemap.start_battle(army, 0);
                                     *battle = Some(battle_new);
                                     self.try_to_send_message(
                                         gamemap,
@@ -453,7 +454,10 @@ impl GameServer {
             self.try_to_send_message(
                 gamemap,
                 1,
-                ServerMessage::State((battle.clone(), gamemap.clone())),
+                ServerMessage::State((
+                    battle.as_mut().map(BattleInfo::for_clients),
+                    gamemap.clone(),
+                )),
             );
         }
 
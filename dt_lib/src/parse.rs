@@ -115,16 +115,29 @@ use math_thingies::add_opt;
 use num::Num;
 
 use super::{
-    battle::{control::Control, troop::Troop},
+    battle::{
+        battlefield::BattleRules,
+        control::Control,
+        troop::{Troop, TroopBattleStats},
+    },
+    battle::battlefield::Field,
     bonuses::*,
+    effects::{
+        custom::{custom_effect, effect_index, CustomEffect, EffectTemplate, EFFECTS},
+        effect::{Effect, EffectInfo, EffectKind, Fire, MoreMoves, Poison},
+    },
     items::item::{ItemInfo, *},
     locale::*,
     map::{
         event::*,
         map::{GameMap, Tilemap, MAP_SIZE},
         object::{MapBuildingdata, Market, ObjectInfo, ObjectType, RecruitUnit, Recruitment},
+        terrain::{TerrainModifier, TERRAIN},
+        tile::TILES,
     },
     mutrc::SendMut,
+    rng::GameRng,
+    spells::{spell_index, Spell, SpellTarget, SPELLS},
     time::time::{Data::*, Time},
     units::{
         unit::{MagicDirection::*, MagicType::*, *},
@@ -139,6 +152,7 @@ use advini::*;
 use ini_core::{Item, Parser};
 use math_thingies::Percent;
 use once_cell::sync::Lazy;
+use rand::SeedableRng;
 use std::{
     any::type_name,
     collections::HashMap,
@@ -291,6 +305,7 @@ pub fn parse_units(path: Option<&str>) -> Result<(Vec<Unit>, (&'static str, Vec<
         let mut regen = Some(0);
 
         let mut next_unit: Vec<String> = Vec::new();
+        let mut lvl_stats = ModifyUnitStats::default();
         for (k, value) in prop.iter() {
             let v = &**value;
             match &**k {
@@ -370,6 +385,72 @@ pub fn parse_units(path: Option<&str>) -> Result<(Vec<Unit>, (&'static str, Vec<
                 "nextunit1" | "nextunit2" | "nextunit3" => {
                     next_unit.push(v.into());
                 }
+                "d-hits" => {
+                    let hits = handle_parse::<i64>(v, &mut error_collector, "d-hits");
+                    lvl_stats.hp.add = hits;
+                    lvl_stats.max_hp.add = hits;
+                }
+                "d-attackblow" | "d-attackhand" => {
+                    lvl_stats.damage.hand.add =
+                        handle_parse::<i64>(v, &mut error_collector, "d-damage_hand");
+                }
+                "d-attackshot" | "d-attackranged" => {
+                    lvl_stats.damage.ranged.add =
+                        handle_parse::<i64>(v, &mut error_collector, "d-damage_ranged");
+                }
+                "d-magicpower" => {
+                    lvl_stats.damage.magic.add =
+                        handle_parse::<i64>(v, &mut error_collector, "d-magic_power");
+                }
+                "d-defenceblow" | "d-defencehand" => {
+                    lvl_stats.defence.hand_units.add =
+                        handle_parse::<i64>(v, &mut error_collector, "d-defence_hand");
+                }
+                "d-defenceshot" | "d-defenceranged" => {
+                    lvl_stats.defence.ranged_units.add =
+                        handle_parse::<i64>(v, &mut error_collector, "d-defence_ranged");
+                }
+                "d-defencemagic" => {
+                    lvl_stats.defence.magic_units.add =
+                        handle_parse::<i64>(v, &mut error_collector, "d-defence_magic");
+                }
+                "d-protectdeath" => {
+                    lvl_stats.defence.death_magic.add =
+                        handle_parse::<i16>(v, &mut error_collector, "d-defence_death_magic");
+                }
+                "d-protectlife" => {
+                    lvl_stats.defence.life_magic.add =
+                        handle_parse::<i16>(v, &mut error_collector, "d-defence_life_magic");
+                }
+                "d-protectelemental" => {
+                    lvl_stats.defence.elemental_magic.add =
+                        handle_parse::<i16>(v, &mut error_collector, "d-defence_elemental_magic");
+                }
+                "d-protectblow" | "d-protecthand" => {
+                    lvl_stats.defence.hand_percent.add =
+                        handle_parse::<i16>(v, &mut error_collector, "d-defence_hand_percent");
+                }
+                "d-protectshot" | "d-protectranged" => {
+                    lvl_stats.defence.ranged_percent.add =
+                        handle_parse::<i16>(v, &mut error_collector, "d-defence_ranged_percent");
+                }
+                "d-manevres" | "d-moves" => {
+                    let moves = handle_parse::<i64>(v, &mut error_collector, "d-moves");
+                    lvl_stats.max_moves.add = moves;
+                    lvl_stats.moves.add = moves;
+                }
+                "d-initiative" | "d-speed" => {
+                    lvl_stats.speed.add =
+                        handle_parse::<i64>(v, &mut error_collector, "d-speed");
+                }
+                "d-vampirizm" => {
+                    lvl_stats.vamp.add =
+                        handle_parse::<i16>(v, &mut error_collector, "d-vamp");
+                }
+                "d-regen" => {
+                    lvl_stats.regen.add =
+                        handle_parse::<i16>(v, &mut error_collector, "d-regen");
+                }
                 "size" => {
                     size = collect_errors(
                         parse_duo_tuple(v),
@@ -407,23 +488,9 @@ pub fn parse_units(path: Option<&str>) -> Result<(Vec<Unit>, (&'static str, Vec<
         };
         let magic_type = match_magictype(&mut error_collector, magic_type, magic_direction);
         let bonus = Bonus::from(bonus_name);
-        let unit_type = match nature {
-            "People" | "" => UnitType::People,
-            "Rogue" => UnitType::Rogue,
-            "Undead" => UnitType::Undead,
-            "Hero" => UnitType::Hero,
-            "Mecha" => UnitType::Mecha,
-            _ => {
-                collect_errors(
-                    MATCH_ERR,
-                    &mut error_collector,
-                    &*format!("Field Nature is invalid: {}", nature),
-                );
-                UnitType::People
-            }
-        };
+        let unit_type = match_unit_type(&mut error_collector, nature);
         let hp = hp.unwrap_or(1);
-        let xp_up = xp_up.unwrap_or(1);
+        let xp_up = xp_up.unwrap_or(140);
         let max_xp = max_xp.unwrap_or(1);
 
         let cost_hire = cost_hire.unwrap_or(1);
@@ -476,7 +543,7 @@ pub fn parse_units(path: Option<&str>) -> Result<(Vec<Unit>, (&'static str, Vec<
                 size: size.unwrap_or((1, 1)),
                 surrender,
                 lvl: LevelUpInfo {
-                    stats: ModifyUnitStats::default(),
+                    stats: lvl_stats,
                     xp_up,
                     max_xp,
                 },
@@ -488,30 +555,36 @@ pub fn parse_units(path: Option<&str>) -> Result<(Vec<Unit>, (&'static str, Vec<
             },
             inventory: UnitInventory {
                 items: vec![None; 4],
+                sets: vec![],
             },
             army: 0,
             bonus,
             effects: vec![],
+            effect_changes: vec![],
         };
         req_assets.push(format!("unit_{}.png", counter.unwrap() - 1));
+        if !next_unit.is_empty() {
+            upgrades.insert(counter.unwrap(), next_unit);
+        }
         units.push((counter.unwrap(), unit));
-        for (index, up) in upgrades.iter() {
-            let upgrade = up
-                .iter()
-                .map(|name| {
-                    units
-                        .iter()
-                        .filter(|unit| unit.1.info.name == *name)
-                        .map(|unit| unit.0)
-                        .next()
-                        .unwrap()
-                })
-                .collect();
-            units[*index].1.info.next_unit = upgrade;
+    }
+    units.sort_by_key(|v| v.0);
+    for (index, up) in upgrades.iter() {
+        let upgrade = up
+            .iter()
+            .filter_map(|name| {
+                let position = units.iter().position(|unit| unit.1.info.name == *name);
+                if position.is_none() {
+                    error_collector.push(format!("Error: NextUnit {} is not found", name));
+                }
+                position
+            })
+            .collect();
+        if let Some(unit) = units.iter_mut().find(|unit| unit.0 == *index) {
+            unit.1.info.next_unit = upgrade;
         }
     }
     if error_collector.is_empty() {
-        units.sort_by_key(|v| v.0);
         Ok((
             units.into_iter().map(|v| v.1).collect(),
             ("assets/Icons", req_assets),
@@ -680,6 +753,23 @@ pub fn parse_objects() -> (Objects, (&'static str, Vec<String>)) {
         ("assets/Objects", req_assets),
     )
 }
+fn match_unit_type(error_collector: &mut Vec<String>, nature: &str) -> UnitType {
+    match nature {
+        "People" | "" => UnitType::People,
+        "Rogue" => UnitType::Rogue,
+        "Undead" => UnitType::Undead,
+        "Hero" => UnitType::Hero,
+        "Mecha" => UnitType::Mecha,
+        _ => {
+            collect_errors(
+                MATCH_ERR,
+                error_collector,
+                &*format!("Field Nature is invalid: {}", nature),
+            );
+            UnitType::People
+        }
+    }
+}
 fn match_magic_variants(
     error_collector: &mut Vec<String>,
     magic_type: String,
@@ -699,18 +789,209 @@ fn match_magic_variants(
         }
     }
 }
+/// Reads a `d-`, `p-` or `f-` stat key into `modify`, returns false if the key is not a stat
+fn parse_modify(modify: &mut ModifyUnitStats, key: &str, value: &str) -> bool {
+    match key {
+        "d-hits" => {
+            modify.max_hp.add = add_opt(modify.max_hp.add, value.parse::<i64>().ok());
+            modify.hp.add = add_opt(modify.hp.add, value.parse::<i64>().ok());
+        }
+        "d-attackblow" => {
+            modify.damage.hand.add = add_opt(modify.damage.hand.add, value.parse().ok())
+        }
+        "d-attackshot" => {
+            modify.damage.ranged.add = add_opt(modify.damage.ranged.add, value.parse().ok())
+        }
+        "d-magicpower" => {
+            modify.damage.magic.add = add_opt(modify.damage.magic.add, value.parse().ok())
+        }
+        "d-defenceblow" => {
+            modify.defence.hand_units.add =
+                add_opt(modify.defence.hand_units.add, value.parse().ok())
+        }
+        "d-defenceshot" => {
+            modify.defence.ranged_units.add =
+                add_opt(modify.defence.ranged_units.add, value.parse().ok())
+        }
+        "d-defencemagic" => {
+            modify.defence.magic_units.add =
+                add_opt(modify.defence.magic_units.add, value.parse().ok())
+        }
+        "d-manevres" => {
+            modify.max_moves.add = add_opt(modify.max_moves.add, value.parse().ok());
+            modify.moves.add = add_opt(modify.moves.add, value.parse().ok());
+        }
+        "d-initiative" => modify.speed.add = add_opt(modify.speed.add, value.parse().ok()),
+        "d-vampirizm" => modify.vamp.add = add_opt(modify.vamp.add, value.parse().ok()),
+        "d-regen" => modify.regen.add = add_opt(modify.regen.add, value.parse().ok()),
+
+        "p-hits" => {
+            modify.max_hp.percent_add = add_opt(
+                modify.max_hp.percent_add,
+                Percent::new(value.parse().unwrap()).into(),
+            );
+            modify.hp.percent_add = add_opt(
+                modify.hp.percent_add,
+                Percent::new(value.parse().unwrap()).into(),
+            );
+        }
+        "p-attackblow" => {
+            modify.damage.hand.percent_add = add_opt(
+                modify.damage.hand.percent_add,
+                Percent::new(value.parse().unwrap()).into(),
+            )
+        }
+        "p-attackshot" => {
+            modify.damage.ranged.percent_add = add_opt(
+                modify.damage.ranged.percent_add,
+                Percent::new(value.parse().unwrap()).into(),
+            )
+        }
+        "p-magicpower" => {
+            modify.damage.magic.percent_add = add_opt(
+                modify.damage.magic.percent_add,
+                Percent::new(value.parse().unwrap()).into(),
+            )
+        }
+        "p-defenceblow" => {
+            modify.defence.hand_units.percent_add = add_opt(
+                modify.defence.hand_units.percent_add,
+                Percent::new(value.parse().unwrap()).into(),
+            )
+        }
+        "p-defenceshot" => {
+            modify.defence.ranged_units.percent_add = add_opt(
+                modify.defence.ranged_units.percent_add,
+                Percent::new(value.parse().unwrap()).into(),
+            )
+        }
+        "p-defencemagic" => {
+            modify.defence.magic_units.percent_add = add_opt(
+                modify.defence.magic_units.percent_add,
+                Percent::new(value.parse().unwrap()).into(),
+            )
+        }
+        "p-protectlife" => {
+            modify.defence.life_magic.percent_add = add_opt(
+                modify.defence.life_magic.percent_add,
+                Percent::new(value.parse().unwrap()).into(),
+            )
+        }
+        "p-protectdeath" => {
+            modify.defence.death_magic.percent_add = add_opt(
+                modify.defence.death_magic.percent_add,
+                Percent::new(value.parse().unwrap()).into(),
+            )
+        }
+        "p-protectelemental" => {
+            modify.defence.elemental_magic.percent_add = add_opt(
+                modify.defence.elemental_magic.percent_add,
+                Percent::new(value.parse().unwrap()).into(),
+            )
+        }
+        "p-manevres" => {
+            modify.max_moves.percent_add = add_opt(
+                modify.max_moves.percent_add,
+                Percent::new(value.parse().unwrap()).into(),
+            );
+            modify.moves.percent_add = add_opt(
+                modify.moves.percent_add,
+                Percent::new(value.parse().unwrap()).into(),
+            );
+        }
+        "p-initiative" => {
+            modify.speed.percent_add = add_opt(
+                modify.speed.percent_add,
+                Percent::new(value.parse().unwrap()).into(),
+            )
+        }
+        "p-vampirizm" => {
+            modify.vamp.percent_add = add_opt(
+                modify.vamp.percent_add,
+                Percent::new(value.parse().unwrap()).into(),
+            )
+        }
+        "p-regen" => {
+            modify.regen.percent_add = add_opt(
+                modify.regen.percent_add,
+                Percent::new(value.parse().unwrap()).into(),
+            )
+        }
+
+        "f-hits" => {
+            modify.max_hp.set = value.parse::<i64>().ok();
+            modify.hp.set = value.parse::<i64>().ok();
+        }
+        "f-attackblow" => modify.damage.hand.set = value.parse().ok(),
+        "f-attackshot" => modify.damage.ranged.set = value.parse().ok(),
+        "f-magicpower" => modify.damage.magic.set = value.parse().ok(),
+        "f-defenceblow" => modify.defence.hand_units.set = value.parse().ok(),
+        "f-defenceshot" => modify.defence.ranged_units.set = value.parse().ok(),
+        "f-defencemagic" => modify.defence.magic_units.set = value.parse().ok(),
+        "f-manevres" => {
+            modify.max_moves.set = value.parse().ok();
+            modify.moves.set = value.parse().ok();
+        }
+        "f-initiative" => modify.speed.set = value.parse().ok(),
+        "f-vampirizm" => modify.vamp.set = value.parse().ok(),
+        "f-regen" => modify.regen.add = value.parse().ok(),
+        _ => return false,
+    }
+    true
+}
+/// Set section of the artefacts file, `Items` lists global indexes of the items and `Pieces` is how many of them must be equipped
+fn parse_item_set(
+    name: String,
+    props: &HashMap<String, String>,
+    error_collector: &mut Vec<String>,
+) -> ItemSet {
+    let mut set = ItemSet {
+        name,
+        items: Vec::new(),
+        pieces: 0,
+        bonus: None,
+        modify: ModifyUnitStats::default(),
+    };
+    for (k, value) in props.iter() {
+        let value = &**value;
+        match &**k {
+            "items" => {
+                set.items = value
+                    .split(',')
+                    .filter_map(|index| handle_parse(index.trim(), error_collector, "items"))
+                    .collect()
+            }
+            "pieces" => set.pieces = handle_parse(value, error_collector, "pieces").unwrap_or(0),
+            "bonus" => set.bonus = Some(Bonus::from(value)),
+            key => {
+                parse_modify(&mut set.modify, key, value);
+            }
+        }
+    }
+    if set.pieces == 0 {
+        set.pieces = set.items.len();
+    }
+    set
+}
 /*
  * d-{stat} - добавление
  * p-{stat} - добавление процента
- * f-{stat} - установить
+ * f-{stat} - установить, у зелий f-hits лечит
+ * heal - лечение зелья, duration - сколько ходов в битве действует зелье, 0 - навсегда
+ * effect - эффект из Effects.ini, который накладывает зелье
  */
 pub fn parse_items(path: Option<&str>, lang: &String) -> (&'static str, Vec<String>) {
     let mut error_collector: Vec<String> = Vec::new();
     let mut items = ITEMS.lock().unwrap();
+    let mut sets = Vec::new();
     let mut req_assets = Vec::new();
 
     let secs = parse_for_sections(path.unwrap_or("Rus_Artefacts.ini"));
     for (sec, props) in secs {
+        if props.contains_key("items") {
+            sets.push(parse_item_set(sec, &props, &mut error_collector));
+            continue;
+        }
         let mut cost: Option<i64> = None;
         let mut description = None;
         let mut name = None;
@@ -721,6 +1002,10 @@ pub fn parse_items(path: Option<&str>, lang: &String) -> (&'static str, Vec<Stri
         let mut magic = None;
         let mut index = None;
         let mut bonus = None;
+        let mut potion = None;
+        let mut heal = None;
+        let mut duration = None;
+        let mut effect = None;
         let itemtype_name = "";
         for (k, value) in props.iter() {
             let value = &**value;
@@ -754,180 +1039,322 @@ pub fn parse_items(path: Option<&str>, lang: &String) -> (&'static str, Vec<Stri
                         "Shield" => ArtifactType::Shield,
                         "Amulet" => ArtifactType::Amulet,
                         "Item" => ArtifactType::Item,
-                        "Potion" => ArtifactType::Amulet,
+                        "Potion" => {
+                            potion = Some(Potion::default());
+                            ArtifactType::Item
+                        }
                         _ => panic!("Wrong Item Type - {}!", value),
                     }
                     .into()
                 }
-                "d-hits" => {
-                    modify.max_hp.add = add_opt(modify.max_hp.add, value.parse::<i64>().ok());
-                    modify.hp.add = add_opt(modify.hp.add, value.parse::<i64>().ok());
-                }
-                "d-attackblow" => {
-                    modify.damage.hand.add = add_opt(modify.damage.hand.add, value.parse().ok())
-                }
-                "d-attackshot" => {
-                    modify.damage.ranged.add = add_opt(modify.damage.ranged.add, value.parse().ok())
-                }
-                "d-magicpower" => {
-                    modify.damage.magic.add = add_opt(modify.damage.magic.add, value.parse().ok())
+                "bonus" => bonus = Some(Bonus::from(value)),
+                "heal" => heal = handle_parse(value, &mut error_collector, "heal"),
+                "duration" => duration = handle_parse(value, &mut error_collector, "duration"),
+                "effect" => match effect_index(value) {
+                    Some(index) => effect = Some(index),
+                    None => {
+                        error_collector.push(format!("Unknown effect {} in item {}", value, sec))
+                    }
+                },
+                key => {
+                    parse_modify(&mut modify, key, value);
                 }
-                "d-defenceblow" => {
-                    modify.defence.hand_units.add =
-                        add_opt(modify.defence.hand_units.add, value.parse().ok())
+            }
+        }
+        let kind = match potion {
+            Some(mut potion) => {
+                // Potions restore hits instead of setting them
+                let set_hits = modify.hp.set.take();
+                modify.max_hp.set = None;
+                potion.heal = heal.or(set_hits.map(|hits| hits.max(0) as u64)).unwrap_or(0);
+                potion.duration = duration.unwrap_or(0);
+                potion.effect = effect;
+                ItemType::Potion(potion)
+            }
+            None => ItemType::Artifact,
+        };
+        items.insert(
+            index.unwrap(),
+            ItemInfo {
+                name: name.expect("No name field").into(),
+                description: description.expect("No description field").into(),
+                cost: {
+                    let cost = cost.expect("No cost field");
+                    if cost > 0 {
+                        cost as u64
+                    } else {
+                        0
+                    }
+                },
+                icon: icon.expect("No icon key").into(),
+                sells: cost.unwrap() > 0,
+                bonus,
+                itemtype: itemtype.expect(&*format!("{name}", name = name.unwrap())),
+                kind,
+                modify,
+            },
+        );
+    }
+    *ITEM_SETS.lock().unwrap() = sets;
+    ("assets/Items", req_assets)
+}
+/// Reads battle modifiers of the terrain, tiles are named like their sprites without the extension
+pub fn parse_terrain(path: Option<&str>) {
+    let mut error_collector: Vec<String> = Vec::new();
+    let mut modifiers = Vec::new();
+    for (sec, props) in parse_for_sections(path.unwrap_or("Terrain.ini")) {
+        let mut modifier = TerrainModifier {
+            name: sec.clone(),
+            tiles: Vec::new(),
+            deco: false,
+            unit_type: None,
+            modify: ModifyUnitStats::default(),
+        };
+        for (k, value) in props.iter() {
+            let value = &**value;
+            match &**k {
+                "tiles" => {
+                    for name in value.split(',').map(|name| name.trim()) {
+                        match TILES
+                            .iter()
+                            .position(|tile| tile.sprite().strip_suffix(".png") == Some(name))
+                        {
+                            Some(tile) => modifier.tiles.push(tile),
+                            None => error_collector
+                                .push(format!("Unknown tile {} in terrain {}", name, sec)),
+                        }
+                    }
                 }
-                "d-defenceshot" => {
-                    modify.defence.ranged_units.add =
-                        add_opt(modify.defence.ranged_units.add, value.parse().ok())
+                "deco" => modifier.deco = str_bool(value.into()),
+                "nature" => modifier.unit_type = Some(match_unit_type(&mut error_collector, value)),
+                key => {
+                    if !parse_modify(&mut modifier.modify, key, value) {
+                        error_collector.push(format!("Unknown key {} in terrain {}", key, sec));
+                    }
                 }
-                "d-defencemagic" => {
-                    modify.defence.magic_units.add =
-                        add_opt(modify.defence.magic_units.add, value.parse().ok())
+            }
+        }
+        modifiers.push(modifier);
+    }
+    if !error_collector.is_empty() {
+        panic!("{}", error_collector.join("\n"));
+    }
+    *TERRAIN.lock().unwrap() = modifiers;
+}
+/// Reads effects defined in data, lifetime 0 makes the effect last until it is removed or the battle ends
+pub fn parse_effects(path: Option<&str>) {
+    let mut error_collector: Vec<String> = Vec::new();
+    let mut effects = Vec::new();
+    for (sec, props) in parse_for_sections(path.unwrap_or("Effects.ini")) {
+        let mut effect = CustomEffect {
+            id: effects.len(),
+            info: EffectInfo { lifetime: 0 },
+            kind: EffectKind::MageSupport,
+            tick_hits: 0,
+            tick_percent: Percent::new(0),
+            modify: ModifyUnitStats::default(),
+            ends_with_battle: true,
+        };
+        for (k, value) in props.iter() {
+            let value = &**value;
+            match &**k {
+                "kind" => {
+                    if let Some(kind) = collect_errors(value.parse(), &mut error_collector, &sec) {
+                        effect.kind = kind;
+                    }
                 }
-                "d-manevres" => {
-                    modify.max_moves.add = add_opt(modify.max_moves.add, value.parse().ok());
-                    modify.moves.add = add_opt(modify.moves.add, value.parse().ok());
+                "lifetime" => {
+                    effect.info.lifetime =
+                        handle_parse(value, &mut error_collector, "lifetime").unwrap_or(0)
                 }
-                "d-initiative" => modify.speed.add = add_opt(modify.speed.add, value.parse().ok()),
-                "d-vampirizm" => modify.vamp.add = add_opt(modify.vamp.add, value.parse().ok()),
-                "d-regen" => modify.regen.add = add_opt(modify.regen.add, value.parse().ok()),
-
-                "p-hits" => {
-                    modify.max_hp.percent_add = add_opt(
-                        modify.max_hp.percent_add,
-                        Percent::new(value.parse().unwrap()).into(),
-                    );
-                    modify.hp.percent_add = add_opt(
-                        modify.hp.percent_add,
-                        Percent::new(value.parse().unwrap()).into(),
-                    );
+                "tickhits" => {
+                    effect.tick_hits =
+                        handle_parse(value, &mut error_collector, "tickhits").unwrap_or(0)
                 }
-                "p-attackblow" => {
-                    modify.damage.hand.percent_add = add_opt(
-                        modify.damage.hand.percent_add,
-                        Percent::new(value.parse().unwrap()).into(),
+                "tickpercent" => {
+                    effect.tick_percent = Percent::new(
+                        handle_parse(value, &mut error_collector, "tickpercent").unwrap_or(0),
                     )
                 }
-                "p-attackshot" => {
-                    modify.damage.ranged.percent_add = add_opt(
-                        modify.damage.ranged.percent_add,
-                        Percent::new(value.parse().unwrap()).into(),
-                    )
+                "battleend" => effect.ends_with_battle = str_bool(value.into()),
+                key => {
+                    if !parse_modify(&mut effect.modify, key, value) {
+                        error_collector.push(format!("Unknown key {} in effect {}", key, sec));
+                    }
                 }
-                "p-magicpower" => {
-                    modify.damage.magic.percent_add = add_opt(
-                        modify.damage.magic.percent_add,
-                        Percent::new(value.parse().unwrap()).into(),
-                    )
+            }
+        }
+        if effect.info.lifetime < 1 {
+            effect.info.lifetime = i32::MAX;
+        }
+        effects.push(EffectTemplate { name: sec, effect });
+    }
+    if !error_collector.is_empty() {
+        panic!("{}", error_collector.join("\n"));
+    }
+    *EFFECTS.lock().unwrap() = effects;
+}
+/// Reads custom bonuses, units and items refer to them by the section name
+pub fn parse_bonuses(path: Option<&str>) {
+    let mut error_collector: Vec<String> = Vec::new();
+    let mut bonuses = Vec::new();
+    for (sec, props) in parse_for_sections(path.unwrap_or("Bonuses.ini")) {
+        let mut bonus = CustomBonus {
+            name: sec.clone(),
+            description: String::new(),
+            hooks: Vec::new(),
+            condition: BonusCondition::default(),
+            modify: None,
+            duration: 0,
+            damage: Percent::new(0),
+            effect: None,
+            dispel: Vec::new(),
+        };
+        let mut modify = ModifyUnitStats::default();
+        for (k, value) in props.iter() {
+            let value = &**value;
+            match &**k {
+                "descript" => bonus.description = value.into(),
+                "hooks" => {
+                    bonus.hooks = value
+                        .split(',')
+                        .filter_map(|hook| {
+                            collect_errors(hook.parse::<BonusHook>(), &mut error_collector, &sec)
+                        })
+                        .collect()
+                }
+                "nature" => {
+                    bonus.condition.unit_type = Some(match_unit_type(&mut error_collector, value))
                 }
-                "p-defenceblow" => {
-                    modify.defence.hand_units.percent_add = add_opt(
-                        modify.defence.hand_units.percent_add,
-                        Percent::new(value.parse().unwrap()).into(),
-                    )
+                "magic" => {
+                    bonus.condition.magic = match value {
+                        "AnyMagic" => Some(MagicVariants::Any),
+                        _ => match_magic_variants(&mut error_collector, value.into()),
+                    }
                 }
-                "p-defenceshot" => {
-                    modify.defence.ranged_units.percent_add = add_opt(
-                        modify.defence.ranged_units.percent_add,
-                        Percent::new(value.parse().unwrap()).into(),
-                    )
+                "field" => {
+                    bonus.condition.field = match value {
+                        "Front" => Some(Field::Front),
+                        "Back" => Some(Field::Back),
+                        "Reserve" => Some(Field::Reserve),
+                        _ => {
+                            error_collector
+                                .push(format!("Unknown field {} in bonus {}", value, sec));
+                            None
+                        }
+                    }
                 }
-                "p-defencemagic" => {
-                    modify.defence.magic_units.percent_add = add_opt(
-                        modify.defence.magic_units.percent_add,
-                        Percent::new(value.parse().unwrap()).into(),
-                    )
+                "duration" => {
+                    bonus.duration =
+                        handle_parse(value, &mut error_collector, "duration").unwrap_or(0)
                 }
-                "p-protectlife" => {
-                    modify.defence.life_magic.percent_add = add_opt(
-                        modify.defence.life_magic.percent_add,
-                        Percent::new(value.parse().unwrap()).into(),
+                "damage" => {
+                    bonus.damage = Percent::new(
+                        handle_parse(value, &mut error_collector, "damage").unwrap_or(0),
                     )
                 }
-                "p-protectdeath" => {
-                    modify.defence.death_magic.percent_add = add_opt(
-                        modify.defence.death_magic.percent_add,
-                        Percent::new(value.parse().unwrap()).into(),
-                    )
+                "effect" => {
+                    bonus.effect = match value {
+                        "Poison" => Some(Effect::from(Poison::default())),
+                        "Fire" => Some(Fire::default().into()),
+                        "MoreMoves" => Some(MoreMoves::default().into()),
+                        _ => match effect_index(value).and_then(custom_effect) {
+                            Some(effect) => Some(effect),
+                            None => {
+                                error_collector
+                                    .push(format!("Unknown effect {} in bonus {}", value, sec));
+                                None
+                            }
+                        },
+                    }
                 }
-                "p-protectelemental" => {
-                    modify.defence.elemental_magic.percent_add = add_opt(
-                        modify.defence.elemental_magic.percent_add,
-                        Percent::new(value.parse().unwrap()).into(),
-                    )
+                "dispel" => {
+                    bonus.dispel = value
+                        .split(',')
+                        .filter_map(|kind| {
+                            collect_errors(kind.parse::<EffectKind>(), &mut error_collector, &sec)
+                        })
+                        .collect()
+                }
+                key => {
+                    if parse_modify(&mut modify, key, value) {
+                        bonus.modify = Some(modify);
+                    } else {
+                        error_collector.push(format!("Unknown key {} in bonus {}", key, sec));
+                    }
                 }
-                "p-manevres" => {
-                    modify.max_moves.percent_add = add_opt(
-                        modify.max_moves.percent_add,
-                        Percent::new(value.parse().unwrap()).into(),
-                    );
-                    modify.moves.percent_add = add_opt(
-                        modify.moves.percent_add,
-                        Percent::new(value.parse().unwrap()).into(),
-                    );
+            }
+        }
+        bonuses.push(bonus);
+    }
+    if !error_collector.is_empty() {
+        panic!("{}", error_collector.join("\n"));
+    }
+    *CUSTOM_BONUSES.lock().unwrap() = bonuses;
+}
+/// Reads spells, armies refer to them by the section name, effects must be parsed before
+pub fn parse_spells(path: Option<&str>) {
+    let mut error_collector: Vec<String> = Vec::new();
+    let mut spells = Vec::new();
+    for (sec, props) in parse_for_sections(path.unwrap_or("Spells.ini")) {
+        let mut spell = Spell {
+            name: sec.clone(),
+            description: String::new(),
+            id: None,
+            cost: 0,
+            target: SpellTarget::Army,
+            range: 0,
+            heal: 0,
+            damage: 0,
+            effect: None,
+            dispel: Vec::new(),
+        };
+        for (k, value) in props.iter() {
+            let value = &**value;
+            match &**k {
+                "descript" => spell.description = value.into(),
+                "id" => spell.id = handle_parse(value, &mut error_collector, "id"),
+                "cost" => {
+                    spell.cost = handle_parse(value, &mut error_collector, "cost").unwrap_or(0)
+                }
+                "target" => {
+                    if let Some(target) = collect_errors(value.parse(), &mut error_collector, &sec)
+                    {
+                        spell.target = target;
+                    }
                 }
-                "p-initiative" => {
-                    modify.speed.percent_add = add_opt(
-                        modify.speed.percent_add,
-                        Percent::new(value.parse().unwrap()).into(),
-                    )
+                "range" => {
+                    spell.range = handle_parse(value, &mut error_collector, "range").unwrap_or(0)
                 }
-                "p-vampirizm" => {
-                    modify.vamp.percent_add = add_opt(
-                        modify.vamp.percent_add,
-                        Percent::new(value.parse().unwrap()).into(),
-                    )
+                "heal" => {
+                    spell.heal = handle_parse(value, &mut error_collector, "heal").unwrap_or(0)
                 }
-                "p-regen" => {
-                    modify.regen.percent_add = add_opt(
-                        modify.regen.percent_add,
-                        Percent::new(value.parse().unwrap()).into(),
-                    )
+                "damage" => {
+                    spell.damage = handle_parse(value, &mut error_collector, "damage").unwrap_or(0)
                 }
-
-                "f-hits" => {
-                    modify.max_hp.set = value.parse::<i64>().ok();
-                    modify.hp.set = value.parse::<i64>().ok();
+                "effect" => {
+                    spell.effect = effect_index(value);
+                    if spell.effect.is_none() {
+                        error_collector.push(format!("Unknown effect {} in spell {}", value, sec));
+                    }
                 }
-                "f-attackblow" => modify.damage.hand.set = value.parse().ok(),
-                "f-attackshot" => modify.damage.ranged.set = value.parse().ok(),
-                "f-magicpower" => modify.damage.magic.set = value.parse().ok(),
-                "f-defenceblow" => modify.defence.hand_units.set = value.parse().ok(),
-                "f-defenceshot" => modify.defence.ranged_units.set = value.parse().ok(),
-                "f-defencemagic" => modify.defence.magic_units.set = value.parse().ok(),
-                "f-manevres" => {
-                    modify.max_moves.set = value.parse().ok();
-                    modify.moves.set = value.parse().ok();
+                "dispel" => {
+                    spell.dispel = value
+                        .split(',')
+                        .filter_map(|kind| {
+                            collect_errors(kind.parse::<EffectKind>(), &mut error_collector, &sec)
+                        })
+                        .collect()
                 }
-                "f-initiative" => modify.speed.set = value.parse().ok(),
-                "f-vampirizm" => modify.vamp.set = value.parse().ok(),
-                "f-regen" => modify.regen.add = value.parse().ok(),
-                "bonus" => bonus = Some(Bonus::from(value)),
-                _ => {}
+                key => error_collector.push(format!("Unknown key {} in spell {}", key, sec)),
             }
         }
-        items.insert(
-            index.unwrap(),
-            ItemInfo {
-                name: name.expect("No name field").into(),
-                description: description.expect("No description field").into(),
-                cost: {
-                    let cost = cost.expect("No cost field");
-                    if cost > 0 {
-                        cost as u64
-                    } else {
-                        0
-                    }
-                },
-                icon: icon.expect("No icon key").into(),
-                sells: cost.unwrap() > 0,
-                bonus,
-                itemtype: itemtype.expect(&*format!("{name}", name = name.unwrap())),
-                modify,
-            },
-        );
+        spells.push(spell);
     }
-    ("assets/Items", req_assets)
+    if !error_collector.is_empty() {
+        panic!("{}", error_collector.join("\n"));
+    }
+    *SPELLS.lock().unwrap() = spells;
 }
 
 trait IsRus {
@@ -1052,6 +1479,8 @@ fn parse_mapdata(
                 let mut main = None;
                 let mut active = true;
                 let mut control = Control::PC;
+                let mut fraction = None;
+                let mut spells = Vec::new();
                 let mut id: Option<usize> = None;
 
                 for prop in props {
@@ -1073,6 +1502,13 @@ fn parse_mapdata(
                             pos = (things.0.parse().unwrap(), things.1.parse().unwrap());
                         }
                         "active" => active = str_bool(prop.1),
+                        "spells" => {
+                            spells = prop
+                                .1
+                                .split(',')
+                                .filter_map(|name| spell_index(name.trim()))
+                                .collect()
+                        }
                         "troops" => {
                             in_troops = prop
                                 .1
@@ -1090,6 +1526,7 @@ fn parse_mapdata(
                                 .collect()
                         }
                         "player" => control = Control::Player(prop.1.parse().unwrap()),
+                        "fraction" => fraction = Some(prop.1.parse().unwrap()),
                         "main" => {
                             let things =
                                 prop.1.split_once(|ch: char| !ch.is_ascii_digit()).unwrap();
@@ -1105,6 +1542,7 @@ fn parse_mapdata(
                                 was_payed: true,
                                 pos: UnitPos::from_index(0),
                                 custom_name: Some(things.1.into()),
+                                battle_stats: TroopBattleStats::default(),
                             };
                             main = Some(SendMut::new(troop));
                         }
@@ -1114,10 +1552,12 @@ fn parse_mapdata(
                 let mut troops = vec![main.unwrap()];
                 troops.append(&mut in_troops);
 
-                armys.push((
-                    id.unwrap(),
-                    Army::new(troops, stats, inv, pos, active, control),
-                ));
+                let mut army = Army::new(troops, stats, inv, pos, active, control);
+                if let Some(fraction) = fraction {
+                    army.fraction = fraction;
+                }
+                army.spells = spells;
+                armys.push((id.unwrap(), army));
             }
             x if x.starts_with("Building") => {
                 let mut id: Option<usize> = None;
@@ -1126,7 +1566,7 @@ fn parse_mapdata(
                 let mut desc = String::new();
                 let mut building_type = None;
                 let mut event = Vec::new();
-                let mut units = Vec::new();
+                let mut recruit_units = Vec::new();
                 let mut recruitment = None;
                 let cost_modify = 1.;
                 let mut market = None;
@@ -1138,6 +1578,7 @@ fn parse_mapdata(
                 let mut defense = Some(0);
                 let mut income = 0;
                 let mut owner = None;
+                let mut garrison = Vec::new();
                 for prop in props {
                     let prop = (prop.0, process_locale(prop.1, locale));
                     match &*prop.0 {
@@ -1160,7 +1601,7 @@ fn parse_mapdata(
                         }
                         "income" => income = prop.1.parse().unwrap(),
                         "recruit" => {
-                            units = prop
+                            recruit_units = prop
                                 .1
                                 .split(",")
                                 .map(|string| string.split_once(";").unwrap())
@@ -1183,6 +1624,27 @@ fn parse_mapdata(
                             }
                         }
                         "events" => event = split_and_parse::<usize>(prop.1),
+                        "garrison" => {
+                            garrison = prop
+                                .1
+                                .split(",")
+                                .map(|string| string.split_once(";").unwrap())
+                                .map(|(num, lvl)| {
+                                    (num.parse::<usize>().unwrap(), lvl.parse::<u64>().unwrap())
+                                })
+                                .map(|(num, lvl)| {
+                                    let mut troop = Troop::empty();
+                                    troop.unit = units[num].clone();
+                                    for _ in 0..lvl {
+                                        troop.unit.level_up();
+                                    }
+                                    // Garrison starts healthy at its level
+                                    troop.unit.stats.hp = troop.unit.stats.max_hp;
+                                    troop.unit.recalc();
+                                    SendMut::new(troop)
+                                })
+                                .collect()
+                        }
                         _ => {}
                     }
                 }
@@ -1194,8 +1656,12 @@ fn parse_mapdata(
                     }
                     .into();
                 }
-                if !units.is_empty() {
-                    recruitment = Recruitment { cost_modify, units }.into();
+                if !recruit_units.is_empty() {
+                    recruitment = Recruitment {
+                        cost_modify,
+                        units: recruit_units,
+                    }
+                    .into();
                 }
                 buildings.push((
                     id.unwrap(),
@@ -1213,6 +1679,7 @@ fn parse_mapdata(
                         defense: defense.unwrap(),
                         income,
                         owner,
+                        garrison,
                     },
                 ));
             }
@@ -1257,6 +1724,9 @@ pub fn parse_story(
     let mut start_mana = Some(0u64);
     let mut start_items = vec![];
     let mut start_time = Time::from_data("1540:1:1:12:0", [YEAR, MONTH, DAY, HOUR, MINUTES]);
+    let mut battle_rules = BattleRules::default();
+    // A new game gets a random seed unless the map fixes it
+    let mut seed = None;
 
     // MapData
     let mut mapdata_path = None;
@@ -1285,6 +1755,19 @@ pub fn parse_story(
                 "start_time" => start_time = Time::from_data(prop.1, [YEAR, MONTH, DAY, HOUR]),
                 "start_gold" => start_gold = handle_parse(prop.1, &mut err_coll, "start_gold"),
                 "start_mana" => start_mana = handle_parse(prop.1, &mut err_coll, "start_mana"),
+                "seed" => seed = handle_parse(prop.1, &mut err_coll, "seed"),
+                "battle_rounds" => {
+                    if let Some(rounds) = handle_parse(prop.1, &mut err_coll, "battle_rounds") {
+                        battle_rules.max_rounds = rounds;
+                    }
+                }
+                "battle_timeout" => {
+                    if let Some(timeout) =
+                        collect_errors(prop.1.parse(), &mut err_coll, "battle_timeout")
+                    {
+                        battle_rules.timeout = timeout;
+                    }
+                }
                 "start_items" => {
                     start_items = prop
                         .1
@@ -1313,6 +1796,8 @@ pub fn parse_story(
         tilemap: mapdata.0,
         buildings: mapdata.2,
         time: start_time,
+        rng: seed.map_or_else(GameRng::from_entropy, GameRng::new),
+        battle_rules,
         ..Default::default()
     };
     (gamemap, events)
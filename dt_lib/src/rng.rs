@@ -0,0 +1,61 @@
+use advini::{Ini, IniParseError};
+use alkahest::alkahest;
+use rand::{Error, RngCore, SeedableRng};
+
+/// Seedable generator for every random decision of the game rules.
+/// It is stored in `GameMap` and `BattleInfo`, so saving or sending them also keeps the random state,
+/// the map INI keeps it as a number.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[alkahest(Deserialize, Serialize, SerializeRef, Formula)]
+pub struct GameRng {
+    pub state: u64,
+}
+impl GameRng {
+    pub fn new(seed: u64) -> Self {
+        Self { state: seed }
+    }
+    /// Creates an independent generator seeded from this one, e.g. for a battle started on the map
+    pub fn fork(&mut self) -> Self {
+        Self::new(self.next_u64())
+    }
+}
+impl RngCore for GameRng {
+    fn next_u32(&mut self) -> u32 {
+        (self.next_u64() >> 32) as u32
+    }
+    // SplitMix64
+    fn next_u64(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9E3779B97F4A7C15);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58476D1CE4E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D049BB133111EB);
+        z ^ (z >> 31)
+    }
+    fn fill_bytes(&mut self, dest: &mut [u8]) {
+        for chunk in dest.chunks_mut(8) {
+            let bytes = self.next_u64().to_le_bytes();
+            chunk.copy_from_slice(&bytes[..chunk.len()]);
+        }
+    }
+    fn try_fill_bytes(&mut self, dest: &mut [u8]) -> Result<(), Error> {
+        self.fill_bytes(dest);
+        Ok(())
+    }
+}
+impl SeedableRng for GameRng {
+    type Seed = [u8; 8];
+    fn from_seed(seed: Self::Seed) -> Self {
+        Self::new(u64::from_le_bytes(seed))
+    }
+    fn seed_from_u64(state: u64) -> Self {
+        Self::new(state)
+    }
+}
+impl Ini for GameRng {
+    fn eat(chars: std::str::Chars) -> Result<(Self, std::str::Chars), IniParseError> {
+        <u64 as Ini>::eat(chars).map(|(state, chars)| (Self::new(state), chars))
+    }
+    fn vomit(&self) -> String {
+        self.state.vomit()
+    }
+}
@@ -0,0 +1,2 @@
+pub mod spell;
+pub use spell::*;
@@ -0,0 +1,210 @@
+use crate::{
+    effects::{custom_effect, EffectKind},
+    units::unit::Unit,
+};
+use once_cell::sync::Lazy;
+use std::str::FromStr;
+use tracing_mutex::stdsync::TracingMutex as Mutex;
+
+/// What a spell is cast on
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SpellTarget {
+    /// One troop of the caster army, in a battle
+    Ally,
+    /// One troop of the enemy army, in a battle
+    Enemy,
+    /// Every troop of the caster army, in a battle or on the map
+    Army,
+    /// Every troop of the army standing on a map tile
+    Tile,
+}
+impl FromStr for SpellTarget {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim() {
+            "Ally" => Ok(Self::Ally),
+            "Enemy" => Ok(Self::Enemy),
+            "Army" => Ok(Self::Army),
+            "Tile" => Ok(Self::Tile),
+            target => Err(format!("Unknown spell target {target}")),
+        }
+    }
+}
+
+/// Spell defined in Spells.ini, armies keep the indexes of the known ones in their spellbook
+#[derive(Clone, Debug)]
+pub struct Spell {
+    pub name: String,
+    pub description: String,
+    /// Number of the spell in `applied_spell` of DTm armies
+    pub id: Option<u8>,
+    /// Mana the army spends on casting
+    pub cost: u64,
+    pub target: SpellTarget,
+    /// How many tiles away from the caster a `Tile` spell reaches
+    pub range: usize,
+    pub heal: u64,
+    /// Hits lost by every target, magic defence does not help
+    pub damage: u64,
+    /// Index in `EFFECTS` of the effect put on every target
+    pub effect: Option<usize>,
+    /// Effect kinds removed from every target
+    pub dispel: Vec<EffectKind>,
+}
+pub static SPELLS: Lazy<Mutex<Vec<Spell>>> = Lazy::new(|| Mutex::new(Vec::new()));
+
+pub fn spell_index(name: &str) -> Option<usize> {
+    SPELLS
+        .lock()
+        .unwrap()
+        .iter()
+        .position(|spell| spell.name == name)
+}
+/// Index in `SPELLS` of the spell with the number from DTm maps
+pub fn spell_by_id(id: u8) -> Option<usize> {
+    SPELLS
+        .lock()
+        .unwrap()
+        .iter()
+        .position(|spell| spell.id == Some(id))
+}
+pub fn get_spell(index: usize) -> Option<Spell> {
+    SPELLS.lock().unwrap().get(index).cloned()
+}
+
+impl Spell {
+    /// Spell can be cast outside of a battle
+    pub fn on_map(&self) -> bool {
+        matches!(self.target, SpellTarget::Army | SpellTarget::Tile)
+    }
+    /// Spell can be cast in a battle
+    pub fn in_battle(&self) -> bool {
+        self.target != SpellTarget::Tile
+    }
+    /// Applies the spell to one unit, false if it changed nothing
+    pub fn apply(&self, unit: &mut Unit) -> bool {
+        if unit.is_dead() {
+            return false;
+        }
+        let mut applied = false;
+        for kind in &self.dispel {
+            applied |= unit.dispel(*kind) > 0;
+        }
+        if let Some(effect) = self.effect.and_then(custom_effect) {
+            applied |= unit.add_effect(effect);
+        }
+        if self.heal > 0 && unit.modified.hp < unit.modified.max_hp {
+            unit.heal(self.heal);
+            applied = true;
+        }
+        if self.damage > 0 {
+            unit.stats.hp -= self.damage as i64;
+            unit.recalc();
+            applied = true;
+        }
+        applied
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        battle::{
+            army::{Army, MAX_TROOPS},
+            battlefield::{handle_action, Action},
+            control::Fraction,
+        },
+        map::{convert::ArmyData, map::GameMap},
+        parse::{parse_effects, parse_spells},
+        rng::GameRng,
+        testing::{gen_army, gen_battle},
+        units::unit::ActionResult,
+    };
+    use zerocopy::FromZeros;
+
+    #[test]
+    fn spells() {
+        parse_effects(Some("dt/Effects.ini"));
+        parse_spells(Some("dt/Spells.ini"));
+        let spell = |name| spell_index(name).unwrap();
+        let (cure, lightning, plague) = (spell("Cure"), spell("Lightning"), spell("Plague"));
+        let (mut armys, mut battle) = gen_battle(0);
+        let active = battle.active_unit.unwrap();
+        let enemy = battle.enemy(active.0);
+        let cell = |army: &Army| -> usize { army.troops[0].get().pos.into() };
+        let (ally_cell, enemy_cell) = (cell(&armys[active.0]), cell(&armys[enemy]));
+        {
+            let unit = &mut armys[active.0].troops[0].get().unit;
+            unit.stats.hp = 50;
+            unit.recalc();
+        }
+        // The spell is not in the spellbook, then the mana is not enough
+        let res = handle_action(Action::Spell(cure, ally_cell), &mut battle, &mut armys).0;
+        assert_eq!(res, None);
+        armys[active.0].spells = vec![cure, lightning, plague];
+        let res = handle_action(Action::Spell(cure, ally_cell), &mut battle, &mut armys).0;
+        assert_eq!(res, None);
+        armys[active.0].stats.mana = 100;
+        // Cells are checked like in `Cell`
+        let empty = (0..*MAX_TROOPS).find(|cell| armys[active.0].hitmap[*cell].is_none());
+        for cell in empty.into_iter().chain([*MAX_TROOPS]) {
+            let res = handle_action(Action::Spell(cure, cell), &mut battle, &mut armys).0;
+            assert_eq!(res, None);
+        }
+        // Tile spells are for the map only
+        let res = handle_action(Action::Spell(plague, enemy_cell), &mut battle, &mut armys).0;
+        assert_eq!(res, None);
+        let (res, _) = handle_action(Action::Spell(cure, ally_cell), &mut battle, &mut armys);
+        assert_eq!(res, Some((ActionResult::Buff, active)));
+        assert_eq!(armys[active.0].troops[0].get().unit.modified.hp, 80);
+        assert_eq!(armys[active.0].stats.mana, 90);
+        assert_eq!(battle.active_unit, Some(active));
+
+        // One spell a round
+        let res = handle_action(Action::Spell(lightning, enemy_cell), &mut battle, &mut armys).0;
+        assert_eq!(res, None);
+        battle.move_count += 1;
+        let res = handle_action(Action::Spell(lightning, enemy_cell), &mut battle, &mut armys).0;
+        assert_eq!(res, Some((ActionResult::Debuff, active)));
+        assert_eq!(armys[enemy].troops[0].get().unit.modified.hp, 75);
+        assert_eq!(armys[enemy].troops[0].get().battle_stats.damage_taken, 25);
+        assert_eq!(armys[active.0].troops[active.1].get().battle_stats.damage_dealt, 25);
+        assert_eq!(battle.stats[battle.side(enemy).unwrap()].lost_hit, 25);
+        assert_eq!(armys[active.0].stats.mana, 70);
+
+        let mut rng = GameRng::new(1);
+        let mut gamemap = GameMap::default();
+        gamemap.armys = vec![gen_army(0, &mut rng), gen_army(1, &mut rng), gen_army(1, &mut rng)];
+        gamemap.armys[1].pos = (3, 4);
+        gamemap.armys[2].pos = (20, 20);
+        gamemap.recalc_armies_hitboxes();
+        gamemap.armys[0].spells = vec![cure, spell("MassHeal"), plague];
+        gamemap.armys[0].stats.mana = 90;
+        {
+            let unit = &mut gamemap.armys[0].troops[0].get().unit;
+            unit.stats.hp = 50;
+            unit.recalc();
+        }
+        assert!(!gamemap.cast_spell(0, cure, (0, 0)));
+        assert!(gamemap.cast_spell(0, spell("MassHeal"), (0, 0)));
+        assert_eq!(gamemap.armys[0].troops[0].get().unit.modified.hp, 70);
+        assert!(!gamemap.cast_spell(0, plague, (5, 5)));
+        // Armies of the same fraction are friends
+        assert!(!gamemap.cast_spell(0, plague, (3, 4)));
+        gamemap.armys[0].fraction = Fraction::Player;
+        assert!(!gamemap.cast_spell(0, plague, (0, 0)));
+        assert!(!gamemap.cast_spell(0, plague, (20, 20)));
+        assert!(gamemap.cast_spell(0, plague, (3, 4)));
+        assert!(gamemap.armys[1].troops[0]
+            .get()
+            .unit
+            .has_effect_kind(EffectKind::Poison));
+        assert_eq!(gamemap.armys[0].stats.mana, 0);
+
+        let mut data = ArmyData::new_zeroed();
+        assert!(data.spells().is_empty());
+        data.applied_spell = 6;
+        assert_eq!(data.spells(), vec![plague]);
+    }
+}
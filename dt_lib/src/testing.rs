@@ -0,0 +1,119 @@
+//! Units, armies and battles the tests of different modules are built on
+use crate::{
+    battle::{
+        army::{Army, ArmyStats},
+        battlefield::BattleInfo,
+        control::Control,
+        troop::Troop,
+    },
+    bonuses::Bonus,
+    parse::parse_units,
+    rng::GameRng,
+    units::{
+        unit::{LevelUpInfo, Unit, UnitInfo, UnitInventory, UnitLvl, UnitStats, UnitType},
+        unitstats::ModifyUnitStats,
+    },
+};
+use rand::{seq::IteratorRandom, Rng};
+
+pub(crate) fn get_unit(moves: i64, speed: i64, army: usize) -> Unit {
+    let mut unit = Unit {
+        bonus: Bonus::NoBonus,
+        stats: UnitStats {
+            speed,
+            hp: 100,
+            max_hp: 100,
+            moves,
+            max_moves: moves,
+            ..Default::default()
+        },
+        modified: UnitStats {
+            speed,
+            ..Default::default()
+        },
+        modify: ModifyUnitStats::default(),
+        info: UnitInfo {
+            name: "".into(),
+            descript: "".into(),
+            cost: 0,
+            cost_hire: 0,
+            icon_index: 0,
+            size: (1, 1),
+            unit_type: UnitType::People,
+            next_unit: Vec::new(),
+            magic_type: None,
+            surrender: None,
+            lvl: LevelUpInfo::empty(),
+        },
+        effects: Vec::new(),
+        effect_changes: Vec::new(),
+        lvl: UnitLvl::empty(),
+        inventory: UnitInventory::empty(),
+        army,
+    };
+    unit.recalc();
+    unit
+}
+fn empty_army() -> Army {
+    Army::new(
+        vec![],
+        ArmyStats {
+            gold: 0,
+            mana: 0,
+            army_name: String::new(),
+        },
+        vec![],
+        (0, 0),
+        true,
+        Control::PC,
+    )
+}
+/// Army of ten plain units with a random speed
+pub(crate) fn gen_army(army_num: usize, rng: &mut GameRng) -> Army {
+    let mut army = empty_army();
+    for _ in 0..10 {
+        army.add_troop(Troop::new(get_unit(1, rng.gen_range(1..10), army_num)).into())
+            .ok();
+    }
+    army
+}
+/// Units of the game data
+pub(crate) fn game_units() -> Vec<Unit> {
+    let Ok((units, _)) = parse_units(Some("dt/Units.ini")) else {
+        panic!("Unit parsing error")
+    };
+    units
+}
+/// Army of ten random units out of `units`
+pub(crate) fn gen_army_from_units(army_num: usize, units: &Vec<Unit>, rng: &mut GameRng) -> Army {
+    let mut army = empty_army();
+    for _ in 0..10 {
+        army.add_troop(
+            Troop::new({
+                let mut unit = units.iter().choose(rng).unwrap().clone();
+                unit.army = army_num;
+                unit
+            })
+            .into(),
+        )
+        .ok();
+    }
+    army
+}
+/// Battle of two armies made by `gen_army`, the battle rng is forked from the seeded one
+pub(crate) fn gen_battle(seed: u64) -> (Vec<Army>, BattleInfo) {
+    let mut rng = GameRng::new(seed);
+    let mut armys = vec![gen_army(0, &mut rng), gen_army(1, &mut rng)];
+    let battle = BattleInfo::new(&mut armys, 0, 1, rng.fork());
+    (armys, battle)
+}
+/// Battle of two armies made by `gen_army_from_units`
+pub(crate) fn gen_battle_from_units(units: &Vec<Unit>, seed: u64) -> (Vec<Army>, BattleInfo) {
+    let mut rng = GameRng::new(seed);
+    let mut armys = vec![
+        gen_army_from_units(0, units, &mut rng),
+        gen_army_from_units(1, units, &mut rng),
+    ];
+    let battle = BattleInfo::new(&mut armys, 0, 1, rng.fork());
+    (armys, battle)
+}
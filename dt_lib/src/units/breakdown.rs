@@ -0,0 +1,250 @@
+use crate::{
+    effects::{Effect, EffectKind, EffectTrait, EFFECTS},
+    items::item::ITEM_SETS,
+    locale::Locale,
+    parse::LOCALE,
+    units::{
+        unit::{Unit, UnitStats},
+        unitstats::ModifyUnitStats,
+    },
+};
+use std::fmt::{Display, Formatter};
+
+/// Stat of a unit a breakdown is made for
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum StatKind {
+    MaxHp,
+    AttackHand,
+    AttackRanged,
+    AttackMagic,
+    DefenceHand,
+    DefenceRanged,
+    DefenceDeath,
+    DefenceLife,
+    DefenceElemental,
+    MaxMoves,
+    Speed,
+    Vamp,
+    Regen,
+}
+impl StatKind {
+    pub const ALL: [Self; 13] = [
+        Self::MaxHp,
+        Self::AttackHand,
+        Self::AttackRanged,
+        Self::AttackMagic,
+        Self::DefenceHand,
+        Self::DefenceRanged,
+        Self::DefenceDeath,
+        Self::DefenceLife,
+        Self::DefenceElemental,
+        Self::MaxMoves,
+        Self::Speed,
+        Self::Vamp,
+        Self::Regen,
+    ];
+    pub fn get(&self, stats: &UnitStats) -> i64 {
+        match self {
+            Self::MaxHp => stats.max_hp,
+            Self::AttackHand => stats.damage.hand as i64,
+            Self::AttackRanged => stats.damage.ranged as i64,
+            Self::AttackMagic => stats.damage.magic as i64,
+            Self::DefenceHand => stats.defence.hand_units as i64,
+            Self::DefenceRanged => stats.defence.ranged_units as i64,
+            Self::DefenceDeath => stats.defence.death_magic.get() as i64,
+            Self::DefenceLife => stats.defence.life_magic.get() as i64,
+            Self::DefenceElemental => stats.defence.elemental_magic.get() as i64,
+            Self::MaxMoves => stats.max_moves,
+            Self::Speed => stats.speed,
+            Self::Vamp => stats.vamp.get() as i64,
+            Self::Regen => stats.regen.get() as i64,
+        }
+    }
+    pub fn label(&self, locale: &Locale) -> String {
+        let magic = |of: &str| {
+            format!("{} {}", locale.get("unitstats_defence_magic"), locale.get(of))
+        };
+        match self {
+            Self::MaxHp => locale.get("unitstats_hp"),
+            Self::AttackHand => locale.get("unitstats_attack_melee"),
+            Self::AttackRanged => locale.get("unitstats_attack_ranged"),
+            Self::AttackMagic => locale.get("unitstats_attack_magic"),
+            Self::DefenceHand => locale.get("unitstats_defence_melee"),
+            Self::DefenceRanged => locale.get("unitstats_defence_ranged"),
+            Self::DefenceDeath => magic("unitstats_defence_magic_death"),
+            Self::DefenceLife => magic("unitstats_defence_magic_life"),
+            Self::DefenceElemental => magic("unitstats_defence_magic_elemental"),
+            Self::MaxMoves => locale.get("unitstats_moves"),
+            Self::Speed => locale.get("unitstats_speed"),
+            Self::Vamp => locale.get("unitstats_vamp"),
+            Self::Regen => locale.get("unitstats_regen"),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct StatPart {
+    pub label: String,
+    pub value: i64,
+}
+
+/// Modified value of a stat split by where it comes from, the parts sum up to the total
+#[derive(Clone, Debug)]
+pub struct StatBreakdown {
+    pub stat: StatKind,
+    pub label: String,
+    pub total: i64,
+    /// Base value goes first, then levels, items, sets, effects and the rest
+    pub parts: Vec<StatPart>,
+}
+impl Display for StatBreakdown {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}: {}", self.label, self.total)?;
+        for (i, part) in self.parts.iter().enumerate() {
+            match (i, part.value < 0) {
+                (0, _) => write!(f, " = {} {}", part.value, part.label)?,
+                (_, true) => write!(f, " - {} {}", -part.value, part.label)?,
+                (_, false) => write!(f, " + {} {}", part.value, part.label)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+enum Source {
+    /// Item, item set or custom effect, the locale may have a translation of the name
+    Named(String),
+    Kind(EffectKind),
+}
+impl Source {
+    fn of_effect(effect: &Effect) -> Self {
+        if let Effect::CustomEffect(custom) = effect {
+            if let Some(template) = EFFECTS.lock().unwrap().get(custom.id) {
+                return Self::Named(template.name.clone());
+            }
+        }
+        Self::Kind(effect.get_kind())
+    }
+    fn label(&self, locale: &Locale) -> String {
+        match self {
+            Self::Named(name) => locale.get(name),
+            Self::Kind(kind) => locale.get(kind.locale_id()),
+        }
+    }
+}
+
+impl Unit {
+    /// Splits every modified stat into the base value, levels, items, sets and effects
+    pub fn stat_breakdown(&self) -> Vec<StatBreakdown> {
+        let mut sources: Vec<(Source, ModifyUnitStats)> = Vec::new();
+        for item in self.inventory.items.iter().flatten() {
+            let info = item.get_info();
+            sources.push((Source::Named(info.name), info.modify));
+        }
+        {
+            let sets = ITEM_SETS.lock().unwrap();
+            for set in self.inventory.sets.iter().filter_map(|index| sets.get(*index)) {
+                sources.push((Source::Named(set.name.clone()), set.modify));
+            }
+        }
+        // Effects change the stats by hand, so they are put on a copy to see by how much
+        let mut copy = self.clone();
+        for effect in &self.effects {
+            let before = copy.modify;
+            effect.clone().update_stats(&mut copy);
+            sources.push((Source::of_effect(effect), copy.modify - before));
+        }
+
+        let mut modify = ModifyUnitStats::default();
+        let mut values = vec![self.stats];
+        for (_, source) in &sources {
+            modify += *source;
+            values.push(modify.apply(&self.stats));
+        }
+        let level_up = self.info.lvl.stats.apply(&UnitStats::empty());
+
+        let locale = LOCALE.lock().unwrap();
+        StatKind::ALL
+            .iter()
+            .map(|stat| {
+                let level = stat.get(&level_up) * self.lvl.lvl as i64;
+                let mut parts = vec![StatPart {
+                    label: locale.get("unitstats_breakdown_base"),
+                    value: stat.get(&self.stats) - level,
+                }];
+                if level != 0 {
+                    parts.push(StatPart {
+                        label: locale.get("unitstats_breakdown_level"),
+                        value: level,
+                    });
+                }
+                for (i, (source, _)) in sources.iter().enumerate() {
+                    let value = stat.get(&values[i + 1]) - stat.get(&values[i]);
+                    if value != 0 {
+                        parts.push(StatPart {
+                            label: source.label(&locale),
+                            value,
+                        });
+                    }
+                }
+                let total = stat.get(&self.modified);
+                let other = total - stat.get(values.last().unwrap());
+                if other != 0 {
+                    parts.push(StatPart {
+                        label: locale.get("unitstats_breakdown_other"),
+                        value: other,
+                    });
+                }
+                StatBreakdown {
+                    stat: *stat,
+                    label: stat.label(&locale),
+                    total,
+                    parts,
+                }
+            })
+            .collect()
+    }
+    /// Breakdown of the stats with a line for every stat
+    pub fn stat_breakdown_text(&self) -> String {
+        self.stat_breakdown()
+            .iter()
+            .map(|stat| stat.to_string())
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{effects::HealMagic, items::item::Item, parse::parse_items, testing::get_unit};
+
+    #[test]
+    fn stat_breakdown() {
+        let _ = parse_items(Some("dt/Rus_Artefacts.ini"), &"Rus".into());
+        let mut unit = get_unit(1, 10, 0);
+        unit.stats.damage.hand = 16;
+        unit.info.lvl.stats.damage.hand.add = Some(2);
+        unit.lvl.lvl = 3;
+        unit.inventory.items = vec![None; 4];
+        unit.recalc();
+        assert!(unit.add_item(Item { index: 48 }, 0));
+        unit.add_effect(HealMagic::new(20));
+
+        let breakdown = unit.stat_breakdown();
+        let stat = |kind: StatKind| breakdown.iter().find(|stat| stat.stat == kind).unwrap();
+        let attack = stat(StatKind::AttackHand);
+        assert_eq!(attack.total, unit.modified.damage.hand as i64);
+        assert_eq!(attack.parts[0].value, 10);
+        assert_eq!(attack.parts[1].value, 6);
+        assert_eq!(attack.parts.last().unwrap().value, 4);
+        let item = Item { index: 48 }.get_info();
+        assert!(stat(StatKind::DefenceHand)
+            .parts
+            .iter()
+            .any(|part| part.label == item.name));
+        for stat in &breakdown {
+            assert_eq!(stat.parts.iter().map(|part| part.value).sum::<i64>(), stat.total);
+        }
+    }
+}
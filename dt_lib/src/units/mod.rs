@@ -1,3 +1,4 @@
+pub mod breakdown;
 pub mod unit;
 pub mod units;
 pub mod unitstats;
@@ -2,6 +2,7 @@ use crate::{
     battle::{
         army::{MAX_LINES, MAX_TROOPS},
         battlefield::{field_type, BattleInfo, Field},
+        events::EffectChange,
     },
     parse::LOCALE,
 };
@@ -11,7 +12,7 @@ use super::unitstats::ModifyUnitStats;
 use crate::{
     bonuses::*,
     effects::effect::*,
-    items::item::Item,
+    items::item::{Item, ITEM_SETS},
     units::unit::{MagicDirection::*, MagicType::*},
 };
 use alkahest::alkahest;
@@ -46,7 +47,7 @@ impl Defence {
     }
 }
 
-#[derive(Copy, Clone, Debug, Add, Sub, Default)]
+#[derive(Copy, Clone, Debug, Add, Sub, Default, PartialEq)]
 #[alkahest(Deserialize, Serialize, SerializeRef, Formula)]
 pub struct Power {
     pub magic: u64,
@@ -126,6 +127,14 @@ impl LevelUpInfo {
             max_xp: 0,
         }
     }
+    /// Experience needed to get from `lvl` to the next level: max_xp * (xp_up / 100) ^ lvl
+    pub fn xp_for_level(&self, lvl: u64) -> u64 {
+        if self.max_xp == 0 {
+            return 0;
+        }
+        let multiplier = (self.xp_up as f64 / 100.).powi(lvl as i32);
+        ((self.max_xp as f64 * multiplier).round() as u64).max(1)
+    }
 }
 
 #[derive(Clone, Debug)]
@@ -165,10 +174,15 @@ impl UnitInfo {
 #[alkahest(Deserialize, Serialize, SerializeRef, Formula)]
 pub struct UnitInventory {
     pub items: Vec<Option<Item>>,
+    /// Indexes in `ITEM_SETS` of the sets working on the unit
+    pub sets: Vec<usize>,
 }
 impl UnitInventory {
     pub fn empty() -> Self {
-        Self { items: vec![] }
+        Self {
+            items: vec![],
+            sets: vec![],
+        }
     }
 }
 
@@ -223,6 +237,69 @@ impl Into<usize> for UnitPos {
     }
 }
 
+/// Cells of the target army an action hits
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[alkahest(Deserialize, Serialize, SerializeRef, Formula)]
+pub enum TargetPattern {
+    Single,
+    /// Whole line of the target
+    Row,
+    /// Target and the cell behind or in front of it
+    Column,
+    /// Target and its neighbours in the line and the column
+    Splash,
+    Army,
+}
+impl TargetPattern {
+    /// Cell indexes hit when the `target` cell is chosen, the chosen one goes first
+    pub fn cells(&self, target: UnitPos) -> Vec<usize> {
+        let columns = *MAX_TROOPS / MAX_LINES;
+        let mut cells: Vec<usize> = vec![target.into()];
+        let mut add = |x: usize, y: usize| {
+            let cell: usize = UnitPos(x, y).into();
+            if x < columns && y < MAX_LINES && !cells.contains(&cell) {
+                cells.push(cell);
+            }
+        };
+        match self {
+            Self::Single => {}
+            Self::Row => (0..columns).for_each(|x| add(x, target.1)),
+            Self::Column => (0..MAX_LINES).for_each(|y| add(target.0, y)),
+            Self::Splash => {
+                if target.0 > 0 {
+                    add(target.0 - 1, target.1);
+                }
+                add(target.0 + 1, target.1);
+                (0..MAX_LINES).for_each(|y| add(target.0, y));
+            }
+            Self::Army => {
+                (0..MAX_LINES).for_each(|y| (0..columns).for_each(|x| add(x, y)));
+            }
+        }
+        cells
+    }
+}
+
+/// How an action reaches a troop of its pattern
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Reach {
+    /// The chosen troop gets the whole action and can strike back
+    Target,
+    /// Another troop gets the damage of the melee or ranged action at its own cell
+    Damage(ActionResult, Power),
+    /// Another troop gets the same action, e.g. a spell on the whole army
+    Action,
+}
+impl Reach {
+    /// How the troops after the chosen one are reached by an action with the `result`
+    pub fn others(result: ActionResult, power: Power) -> Self {
+        match result {
+            ActionResult::Melee | ActionResult::Ranged => Self::Damage(result, power),
+            _ => Self::Action,
+        }
+    }
+}
+
 #[derive(Clone, Copy, Debug, PartialEq)]
 #[alkahest(Deserialize, Serialize, SerializeRef, Formula)]
 pub enum UnitType {
@@ -233,6 +310,20 @@ pub enum UnitType {
     Undead,
     Rogue,
 }
+impl UnitType {
+    /// Whether effects of the kind, put by the magic if any, do not work on the unit type
+    pub fn is_immune(&self, kind: EffectKind, magic: Option<MagicType>) -> bool {
+        match (self, kind, magic) {
+            (Self::Mecha, EffectKind::Poison, _) => true,
+            (Self::Mecha, EffectKind::MageSupport, Some(Life(_) | Death(_))) => true,
+            (Self::Undead, EffectKind::MageSupport, Some(Life(_))) => true,
+            (Self::Rogue | Self::Hero | Self::People, EffectKind::MageSupport, Some(Death(_))) => {
+                true
+            }
+            _ => false,
+        }
+    }
+}
 
 #[derive(Clone, Debug)]
 #[alkahest(Deserialize, Serialize, SerializeRef, Formula)]
@@ -246,6 +337,8 @@ pub struct Unit {
     pub army: usize,
     pub bonus: Bonus,
     pub effects: Vec<Effect>,
+    /// Effects put on the unit or taken off it since the battle last reported them
+    pub effect_changes: Vec<EffectChange>,
 }
 
 fn heal_unit(
@@ -272,17 +365,14 @@ fn bless_unit(
     damage: Power,
     magic_type: MagicType,
 ) -> Option<ActionResult> {
-    match (target.info.unit_type, magic_type) {
-        (UnitType::Rogue | UnitType::Hero | UnitType::People, Death(_)) => None,
-        (UnitType::Undead, Life(_)) => None,
-        (UnitType::Mecha, _) => None,
-        _ => {
-            if !target.has_effect_kind(EffectKind::MageSupport) {
-                target.add_effect(HealMagic::new(damage.magic));
-                return Some(ActionResult::Buff);
-            }
-            None
-        }
+    let effect = HealMagic {
+        magic_type,
+        ..HealMagic::new(damage.magic)
+    };
+    if target.add_effect(effect) {
+        Some(ActionResult::Buff)
+    } else {
+        None
     }
 }
 fn heal_bless(
@@ -305,8 +395,7 @@ fn heal_bless(
 }
 
 fn elemental_bless(me: &mut Unit, target: &mut Unit, damage: Power) -> Option<ActionResult> {
-    if !target.has_effect_kind(EffectKind::MageSupport) {
-        target.add_effect(ElementalSupport::new(damage.magic));
+    if target.add_effect(ElementalSupport::new(damage.magic)) {
         return Some(ActionResult::Debuff);
     };
     None
@@ -324,10 +413,11 @@ fn magic_curse(
         }
         _ => {}
     }
-    if !target.has_effect_kind(EffectKind::MageCurse) {
-        target.add_effect(AttackMagic::new(
-            target.correct_damage(&damage, me.info.magic_type).magic,
-        ));
+    let effect = AttackMagic {
+        magic_type,
+        ..AttackMagic::new(target.correct_damage(&damage, me.info.magic_type).magic)
+    };
+    if target.add_effect(effect) {
         Some(ActionResult::Debuff)
     } else {
         None
@@ -335,10 +425,8 @@ fn magic_curse(
 }
 
 fn elemental_curse(me: &mut Unit, target: &mut Unit, damage: Power) -> Option<ActionResult> {
-    if !target.has_effect_kind(EffectKind::MageCurse) {
-        target.add_effect(DisableMagic::new(
-            target.correct_damage(&damage, me.info.magic_type).magic,
-        ));
+    let effect = DisableMagic::new(target.correct_damage(&damage, me.info.magic_type).magic);
+    if target.add_effect(effect) {
         Some(ActionResult::Debuff)
     } else {
         None
@@ -392,12 +480,17 @@ fn get_magic_direction(magic_type: MagicType) -> MagicDirection {
         Elemental(direction) => direction,
     }
 }
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[alkahest(Deserialize, Serialize, SerializeRef, Formula)]
 pub enum ActionResult {
     Buff,
     Debuff,
     Melee,
     Ranged,
     Move,
+    Defend,
+    Wait,
+    Surrender,
 }
 impl Unit {
     pub fn recalc(&mut self) {
@@ -419,6 +512,7 @@ impl Unit {
             lvl,
             inventory,
             effects,
+            effect_changes: Vec::new(),
             army,
             modify: ModifyUnitStats::default(),
             bonus,
@@ -525,6 +619,73 @@ impl Unit {
             }
         };
     }
+    /// Cells an action with the `result` hits, spells of mages acting on all sides cover the whole army
+    pub fn target_pattern(&self, result: ActionResult) -> TargetPattern {
+        match self.bonus {
+            Bonus::ManyTargets => TargetPattern::Row,
+            Bonus::ColumnStrike => TargetPattern::Column,
+            Bonus::Splash => TargetPattern::Splash,
+            _ => match (self.info.magic_type, result) {
+                (_, ActionResult::Melee | ActionResult::Ranged) => TargetPattern::Single,
+                (Some(magic_type), _) if get_magic_direction(magic_type) == ToAll => {
+                    TargetPattern::Army
+                }
+                _ => TargetPattern::Single,
+            },
+        }
+    }
+    /// Damage the unit sends to the `target` before its defence and the channel it goes through,
+    /// `attack` and the previews pick ranged or melee with it, None stands for magic
+    pub fn attack_power(
+        &self,
+        target: &Unit,
+        target_pos: UnitPos,
+        my_pos: UnitPos,
+    ) -> (Option<ActionResult>, Power) {
+        let mut damage = self.modified.damage;
+        let is_enemy = self.army != target.army;
+        let my_field = field_type(my_pos.into(), *MAX_TROOPS);
+        if damage.ranged > 0
+            && (target_pos.1 == my_pos.1 && abs(target_pos.0 as i64 - my_pos.0 as i64) < 2
+                || my_field == Field::Back)
+            && is_enemy
+        {
+            damage.hand = 0;
+            damage.magic = 0;
+            (Some(ActionResult::Ranged), damage)
+        } else if damage.hand > 0 && my_field != Field::Back && target_pos.1 == 1 && is_enemy {
+            damage.ranged = 0;
+            damage.magic = 0;
+            (Some(ActionResult::Melee), damage)
+        } else {
+            damage.hand = 0;
+            damage.ranged = 0;
+            (None, damage)
+        }
+    }
+    /// Acts on the `target` at `target_pos` as the `reach` tells, only the chosen target strikes back
+    pub fn reach(
+        &mut self,
+        target: &mut Unit,
+        target_pos: UnitPos,
+        my_pos: UnitPos,
+        reach: Reach,
+        battle: &BattleInfo,
+    ) -> Option<ActionResult> {
+        let res = match reach {
+            Reach::Damage(result, power) => {
+                target.being_attacked(&power, self, target_pos, my_pos, battle);
+                Some(result)
+            }
+            Reach::Target | Reach::Action => self.attack(target, target_pos, my_pos, battle),
+        };
+        if reach == Reach::Target && res.is_some() && !target.is_dead() {
+            for bonus in target.bonuses() {
+                bonus.on_struck(target, self, target_pos, my_pos, battle);
+            }
+        }
+        res
+    }
     pub fn attack(
         &mut self,
         target: &mut Unit,
@@ -532,12 +693,11 @@ impl Unit {
         my_pos: UnitPos,
         battle: &BattleInfo,
     ) -> Option<ActionResult> {
-        let effected = self.modified;
         let is_enemy = self.army != target.army;
         let my_field = field_type(my_pos.into(), *MAX_TROOPS);
         let is_in_back = my_field == Field::Back;
         let enemy_field = field_type(target_pos.into(), *MAX_TROOPS);
-        let mut damage = effected.damage;
+        let (channel, damage) = self.attack_power(target, target_pos, my_pos);
         let enemy_in_reserve = enemy_field == Field::Reserve;
         let me_in_reserve = my_field == Field::Reserve;
         let both_in_reserve = me_in_reserve && enemy_in_reserve;
@@ -548,20 +708,9 @@ impl Unit {
             || (!is_enemy && (both_not_in_reserve || both_in_reserve));
         return if !a {
             None
-        } else if damage.ranged > 0
-            && (target_pos.1 == my_pos.1 && abs(target_pos.0 as i64 - my_pos.0 as i64) < 2
-                || my_field == Field::Back)
-            && is_enemy
-        {
-            damage.hand = 0;
-            damage.magic = 0;
-            target.being_attacked(&damage, self, target_pos, my_pos, battle);
-            Some(ActionResult::Ranged)
-        } else if damage.hand > 0 && !is_in_back && target_pos.1 == 1 && is_enemy {
-            damage.ranged = 0;
-            damage.magic = 0;
+        } else if let Some(result) = channel {
             target.being_attacked(&damage, self, target_pos, my_pos, battle);
-            Some(ActionResult::Melee)
+            Some(result)
         } else {
             match self.info.magic_type {
                 None => None,
@@ -617,8 +766,6 @@ impl Unit {
                             Elemental(_) => elemental_curse(self, target, damage),
                         },
                         (StrikeOnly, _, true) => {
-                            damage.hand = 0;
-                            damage.ranged = 0;
                             target.being_attacked(&damage, self, my_pos, target_pos, battle);
                             Some(ActionResult::Debuff)
                         }
@@ -649,6 +796,53 @@ impl Unit {
         return false;
     }
 
+    /// Adds experience and levels the unit up for every crossed threshold, returns the amount of gained levels
+    pub fn gain_xp(&mut self, xp: u64) -> u64 {
+        self.lvl.xp += xp;
+        let mut levels = 0;
+        while self.lvl.max_xp > 0 && self.lvl.xp >= self.lvl.max_xp {
+            self.lvl.xp -= self.lvl.max_xp;
+            self.level_up();
+            levels += 1;
+        }
+        levels
+    }
+    /// Raises the stats by the level bonus of the unit type, the hits still have to be healed
+    pub fn level_up(&mut self) {
+        self.lvl.lvl += 1;
+        self.lvl.max_xp = self.info.lvl.xp_for_level(self.lvl.lvl);
+        let hp = self.stats.hp;
+        self.stats = self.info.lvl.stats.apply(&self.stats);
+        self.stats.hp = hp;
+        self.recalc();
+    }
+    pub fn can_promote(&self) -> bool {
+        self.lvl.lvl > 0 && !self.info.next_unit.is_empty()
+    }
+    /// Turns the unit into the `choice` of its `next_unit` types. The unit keeps its army, items,
+    /// effects and the changes they made to the stats, everything else comes from the new type:
+    /// level and experience start over, the hits are full and the bonus is the one of the new type.
+    pub fn promote(&mut self, units: &Vec<Unit>, choice: usize) -> bool {
+        if !self.can_promote() {
+            return false;
+        }
+        let Some(next) = self
+            .info
+            .next_unit
+            .get(choice)
+            .and_then(|index| units.get(*index))
+        else {
+            return false;
+        };
+        let mut unit = next.clone();
+        unit.army = self.army;
+        unit.modify = self.modify;
+        unit.inventory = self.inventory.clone();
+        unit.effects = std::mem::take(&mut self.effects);
+        unit.recalc();
+        *self = unit;
+        true
+    }
     pub fn get_effected_stats(&self) -> UnitStats {
         self.modified
     }
@@ -667,12 +861,55 @@ impl Unit {
         self.stats.hp = -self.modified.hp;
         self.recalc();
     }
+    /// Puts the effect following the stack policy of its kind, false if it was not applied
     pub fn add_effect(&mut self, effect: impl Into<Effect>) -> bool {
-        self.effects.push(effect.into());
-        self.effects.last_mut().unwrap().clone().update_stats(self);
+        let mut effect = effect.into();
+        let kind = effect.get_kind();
+        if self.info.unit_type.is_immune(kind, effect.get_magic()) {
+            return false;
+        }
+        if let Some(index) = self.effects.iter().position(|old| old.get_kind() == kind) {
+            match kind.stack_policy() {
+                StackPolicy::Stack => {}
+                StackPolicy::Ignore => return false,
+                StackPolicy::Refresh => {
+                    if let (Some(old), Some(new)) =
+                        (self.effects[index].info_mut(), effect.info_mut())
+                    {
+                        old.lifetime = old.lifetime.max(new.lifetime);
+                    }
+                    self.effect_changes.push(EffectChange::Applied(kind));
+                    return true;
+                }
+                StackPolicy::ReplaceStronger => {
+                    if effect.power() <= self.effects[index].power() {
+                        return false;
+                    }
+                    self.effects.remove(index).kill(self);
+                }
+            }
+        }
+        // The stored effect keeps what it changed to take it back when it ends
+        effect.update_stats(self);
+        self.effects.push(effect);
+        self.effect_changes.push(EffectChange::Applied(kind));
         self.recalc();
         true
     }
+    /// Removes all the effects of the kind, returns how many were removed
+    pub fn dispel(&mut self, kind: EffectKind) -> usize {
+        let (dispelled, kept): (Vec<Effect>, Vec<Effect>) = std::mem::take(&mut self.effects)
+            .into_iter()
+            .partition(|effect| effect.get_kind() == kind);
+        self.effects = kept;
+        let count = dispelled.len();
+        for mut effect in dispelled {
+            effect.kill(self);
+            self.effect_changes.push(EffectChange::Expired(kind));
+        }
+        self.recalc();
+        count
+    }
     pub fn add_item(&mut self, item: Item, index: usize) -> bool {
         if let Some(Some(_)) = self.inventory.items.get(index) {
             return false;
@@ -680,6 +917,7 @@ impl Unit {
         if item.can_equip(&*self) {
             self.modify += item.get_info().modify;
             self.inventory.items[index] = Some(item);
+            self.update_sets();
             self.recalc();
             true
         } else {
@@ -695,8 +933,27 @@ impl Unit {
         self.inventory.items.insert(index, None);
         let item_info = item.get_info();
         self.modify -= item_info.modify;
+        self.update_sets();
         self.recalc();
     }
+    /// Applies the item sets completed by the equipped items and takes off the broken ones
+    fn update_sets(&mut self) {
+        let sets = ITEM_SETS.lock().unwrap();
+        for (index, set) in sets.iter().enumerate() {
+            let works = set.works(&self.inventory);
+            match self.inventory.sets.iter().position(|set| *set == index) {
+                Some(position) if !works => {
+                    self.inventory.sets.remove(position);
+                    self.modify -= set.modify;
+                }
+                None if works => {
+                    self.inventory.sets.push(index);
+                    self.modify += set.modify;
+                }
+                _ => {}
+            }
+        }
+    }
     pub fn get_bonus(&self) -> Bonus {
         let mut bonus = self.bonus;
         if let Some(item) = self
@@ -718,6 +975,18 @@ impl Unit {
         };
         bonus.clone()
     }
+    /// Bonus of the unit or its items together with the bonuses of the complete sets, all of them work
+    pub fn bonuses(&self) -> Vec<Bonus> {
+        let sets = ITEM_SETS.lock().unwrap();
+        std::iter::once(self.get_bonus())
+            .chain(
+                self.inventory
+                    .sets
+                    .iter()
+                    .filter_map(|set| sets.get(*set).and_then(|set| set.bonus)),
+            )
+            .collect()
+    }
     pub fn being_attacked(
         &mut self,
         damage: &Power,
@@ -726,18 +995,8 @@ impl Unit {
         attacker_pos: UnitPos,
         battle: &BattleInfo,
     ) -> u64 {
-        let corrected_damage = self.correct_damage(damage, sender.info.magic_type);
-        let unit_bonus = sender.get_bonus();
         let corrected_damage =
-            unit_bonus.on_attacking(corrected_damage, self, sender, my_pos, attacker_pos);
-        let corrected_damage = self.get_bonus().on_attacked(
-            corrected_damage,
-            self,
-            sender,
-            my_pos,
-            attacker_pos,
-            battle,
-        );
+            self.corrected_damage(damage, sender, my_pos, attacker_pos, battle);
         let mut corrected_damage_units =
             corrected_damage.magic + corrected_damage.ranged + corrected_damage.hand;
         if corrected_damage_units == 0 {
@@ -751,6 +1010,26 @@ impl Unit {
         self.recalc();
         corrected_damage_units
     }
+    /// Damage that is left after the defence of the unit and the bonuses of both sides
+    pub fn corrected_damage(
+        &mut self,
+        damage: &Power,
+        sender: &mut Unit,
+        my_pos: UnitPos,
+        attacker_pos: UnitPos,
+        battle: &BattleInfo,
+    ) -> Power {
+        let mut corrected_damage = self.correct_damage(damage, sender.info.magic_type);
+        for bonus in sender.bonuses() {
+            corrected_damage =
+                bonus.on_attacking(corrected_damage, self, sender, my_pos, attacker_pos);
+        }
+        for bonus in self.bonuses() {
+            corrected_damage =
+                bonus.on_attacked(corrected_damage, self, sender, my_pos, attacker_pos, battle);
+        }
+        corrected_damage
+    }
     pub fn correct_damage(&self, damage: &Power, magic_type: Option<MagicType>) -> Power {
         let defence: Defence = self.modified.defence;
         let percent_100 = Percent::new(100);
@@ -775,27 +1054,32 @@ impl Unit {
         }
     }
     pub fn tick(&mut self) -> bool {
-        let mut i = 0;
-        loop {
-            if i >= self.effects.len() {
-                break;
-            }
-            let mut effect = self.effects.remove(i);
+        // Every effect ticks once, even if it changes the hits of the unit
+        let mut effects = std::mem::take(&mut self.effects);
+        for effect in effects.iter_mut() {
             effect.tick(self);
+        }
+        for mut effect in effects {
             if effect.is_dead() {
+                self.effect_changes.push(EffectChange::Expired(effect.get_kind()));
                 effect.kill(self);
-                i -= 1;
             } else {
                 self.effects.push(effect);
-            };
-            i += 1;
+            }
+        }
+        for bonus in self.bonuses() {
+            bonus.on_tick(self);
         }
-        self.get_bonus().on_tick(self);
         self.recalc();
         true
     }
 }
 fn get_bonus_info(bonus: Bonus) -> (String, String) {
+    if let Bonus::Custom(index) = bonus {
+        if let Some(custom) = get_custom_bonus(index) {
+            return (custom.name, custom.description);
+        }
+    }
     let locale_ids = bonus.locale_id();
     let locale = LOCALE.lock().unwrap();
     (locale.get(locale_ids.0), locale.get(locale_ids.1))
@@ -978,3 +1262,40 @@ pub fn calclate_unit_power(unit: &Unit) -> f32 {
         + vamp_points * 3.
         + health_points * bonus_health_modifier
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::testing::game_units;
+
+    #[test]
+    fn unit_levelling() {
+        let units = game_units();
+        let mut unit = units[0].clone();
+        let info = unit.info.lvl.clone();
+        let max_hp = unit.stats.max_hp;
+        unit.stats.hp = 1;
+        assert_eq!(unit.gain_xp(info.max_xp - 1), 0);
+        assert_eq!(unit.gain_xp(1), 1);
+        assert_eq!(unit.lvl.max_xp, info.xp_for_level(1));
+        assert_eq!(unit.stats.max_hp, info.stats.max_hp.apply(max_hp));
+        // Only the maximum grows, the hits are not healed by a new level
+        assert_eq!(unit.stats.hp, 1);
+        let next = info.xp_for_level(1) + info.xp_for_level(2);
+        assert_eq!(unit.gain_xp(next), 2);
+        assert_eq!(unit.lvl.lvl, 3);
+        assert_eq!(unit.lvl.xp, 0);
+
+        let mut unit = units
+            .iter()
+            .find(|unit| !unit.info.next_unit.is_empty())
+            .unwrap()
+            .clone();
+        let next = unit.info.next_unit[0];
+        assert!(!unit.promote(&units, 0));
+        unit.gain_xp(unit.lvl.max_xp);
+        assert!(unit.promote(&units, 0));
+        assert_eq!(unit.info.name, units[next].info.name);
+        assert_eq!(unit.lvl.lvl, units[next].lvl.lvl);
+        assert_eq!(unit.stats.hp, units[next].stats.hp);
+    }
+}
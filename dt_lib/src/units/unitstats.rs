@@ -119,7 +119,7 @@ impl ModifyDefence {
         n_defence.hand_percent = self.hand_percent.apply(defence.hand_percent);
         n_defence.ranged_percent = self.ranged_percent.apply(defence.ranged_percent);
         n_defence.magic_units = self.magic_units.apply(defence.magic_units);
-        n_defence.ranged_units = self.magic_units.apply(defence.magic_units);
+        n_defence.ranged_units = self.ranged_units.apply(defence.ranged_units);
         n_defence.hand_units = self.hand_units.apply(defence.hand_units);
         n_defence
     }
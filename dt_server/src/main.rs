@@ -52,7 +52,10 @@ use dt_lib::{
         tile::*,
     },
     network::net::*,
-    parse::{parse_items, parse_objects, parse_settings, parse_story, parse_units},
+    parse::{
+        parse_bonuses, parse_effects, parse_items, parse_objects, parse_settings, parse_spells,
+        parse_story, parse_terrain, parse_units,
+    },
     time::time::Data as TimeData,
     units::{
         unit::{ActionResult, Unit, UnitPos},
@@ -85,7 +88,11 @@ fn setup_connection(state: &mut State) {
 }
 fn setup() {
     let settings = parse_settings();
+    parse_effects(None);
+    parse_bonuses(None);
+    parse_spells(None);
     parse_items(None, &settings.locale);
+    parse_terrain(None);
     let res = parse_units(None);
     if let Err(err) = res {
         panic!("{}", err);
@@ -0,0 +1,316 @@
+use clap::{Parser, ValueEnum};
+use dt_lib::{
+    battle::{
+        ai::play_pc_turns,
+        army::{Army, ArmyStats, TroopType},
+        battlefield::{handle_action, move_thing, Action, BattleInfo, BattleRules, TimeoutRule},
+        control::Control,
+        troop::Troop,
+    },
+    items::item::{Item, ITEMS},
+    parse::{parse_bonuses, parse_effects, parse_items, parse_units},
+    rng::GameRng,
+    units::unit::Unit,
+};
+use rand::seq::SliceRandom;
+use serde::Serialize;
+use std::{io::stdout, process::exit};
+
+/// Battle that isn't over after so many steps is stuck and reported as an error
+const MAX_STEPS: u64 = 100_000;
+
+#[derive(Clone, Copy, PartialEq, ValueEnum)]
+enum Policy {
+    /// Every unit acts on a random possible target
+    Random,
+    /// Both armies are played by the battle AI
+    Ai,
+}
+
+#[derive(Parser)]
+struct Args {
+    /// Defending army: comma separated troops written as `unit[;level[;item+item]]`, e.g. `1;2;5+7,3,3`
+    #[arg()]
+    army1: String,
+    /// Attacking army, same format as army1
+    #[arg()]
+    army2: String,
+    /// Amount of battles to simulate
+    #[arg(short, long, default_value_t = 1000)]
+    battles: u64,
+    /// Who decides the actions of the units
+    #[arg(short, long, value_enum, default_value_t = Policy::Ai)]
+    policy: Policy,
+    /// Round limit of a battle
+    #[arg(short, long, default_value_t = BattleRules::default().max_rounds)]
+    rounds: u64,
+    /// What happens when the round limit is reached: AttackerLoses, DefenderLoses, Draw or MorePower
+    #[arg(short, long, default_value = "AttackerLoses")]
+    timeout: TimeoutRule,
+    /// Seed of the first battle, every next battle uses the next seed
+    #[arg(short, long, default_value_t = 0)]
+    seed: u64,
+    /// Print the report as JSON
+    #[arg(short, long, default_value_t = false)]
+    json: bool,
+    /// Path to the units file
+    #[arg(long, default_value = "dt/Units.ini")]
+    units: String,
+    /// Path to the artefacts file
+    #[arg(long, default_value = "dt/Rus_Artefacts.ini")]
+    items: String,
+    /// Path to the effects file
+    #[arg(long, default_value = "dt/Effects.ini")]
+    effects: String,
+    /// Path to the custom bonuses file
+    #[arg(long, default_value = "dt/Bonuses.ini")]
+    bonuses: String,
+}
+
+struct TroopSpec {
+    unit: usize,
+    level: u64,
+    items: Vec<usize>,
+}
+
+fn parse_spec(spec: &str) -> Result<Vec<TroopSpec>, String> {
+    spec.split(',')
+        .map(|troop| {
+            let mut parts = troop.trim().split(';');
+            let unit = parts
+                .next()
+                .and_then(|unit| unit.trim().parse().ok())
+                .ok_or(format!("Wrong unit index in `{troop}`"))?;
+            let level = match parts.next() {
+                Some(level) => level
+                    .trim()
+                    .parse()
+                    .map_err(|_| format!("Wrong level in `{troop}`"))?,
+                None => 0,
+            };
+            let items = match parts.next() {
+                Some(items) => items
+                    .split('+')
+                    .map(|item| item.trim().parse())
+                    .collect::<Result<_, _>>()
+                    .map_err(|_| format!("Wrong items in `{troop}`"))?,
+                None => Vec::new(),
+            };
+            Ok(TroopSpec { unit, level, items })
+        })
+        .collect()
+}
+
+fn build_army(specs: &Vec<TroopSpec>, units: &Vec<Unit>, army_num: usize) -> Result<Army, String> {
+    let mut army = Army::new(
+        vec![],
+        ArmyStats::default(),
+        vec![],
+        (0, 0),
+        true,
+        Control::PC,
+    );
+    for spec in specs {
+        let mut unit = units
+            .get(spec.unit)
+            .ok_or(format!("No unit with index {}", spec.unit))?
+            .clone();
+        unit.army = army_num;
+        for _ in 0..spec.level {
+            unit.level_up();
+        }
+        unit.stats.hp = unit.stats.max_hp;
+        unit.recalc();
+        for (slot, item) in spec.items.iter().enumerate() {
+            if slot >= unit.inventory.items.len() {
+                return Err(format!("{} has no place for item {}", unit.info.name, item));
+            }
+            if !ITEMS.lock().unwrap().contains_key(item) {
+                return Err(format!("No item with index {}", item));
+            }
+            if !unit.add_item(Item { index: *item }, slot) {
+                return Err(format!("{} can't equip item {}", unit.info.name, item));
+            }
+        }
+        army
+            .add_troop(Troop::new(unit).into())
+            .map_err(|_| format!("No place for troop {} in army {}", spec.unit, army_num + 1))?;
+    }
+    Ok(army)
+}
+
+/// Plays one move of the active unit with a random possible action, defends or waits if it fails
+fn random_turn(battle: &mut BattleInfo, armys: &mut Vec<Army>) {
+    let targets = battle
+        .can_interact
+        .clone()
+        .unwrap_or_default()
+        .into_iter()
+        .filter_map(|(army, pos)| Some((battle.side(army)?, pos)))
+        .collect::<Vec<_>>();
+    let target = targets.choose(&mut battle.rng).map(|&(side, pos)| Action::Cell(pos, side));
+    for action in target.into_iter().chain([Action::Defend, Action::Wait]) {
+        if handle_action(action, battle, armys).0.is_some() {
+            break;
+        }
+    }
+}
+
+#[derive(Default)]
+struct SideReport {
+    wins: u64,
+    names: Vec<String>,
+    survived: Vec<u64>,
+}
+
+#[derive(Serialize)]
+struct UnitReport {
+    name: String,
+    /// Share of the battles the troop survived
+    survival: f64,
+}
+#[derive(Serialize)]
+struct ArmyReport {
+    wins: u64,
+    win_rate: f64,
+    units: Vec<UnitReport>,
+}
+#[derive(Serialize)]
+struct Report {
+    battles: u64,
+    draws: u64,
+    average_turns: f64,
+    army1: ArmyReport,
+    army2: ArmyReport,
+}
+impl ArmyReport {
+    fn new(side: SideReport, battles: f64) -> Self {
+        Self {
+            wins: side.wins,
+            win_rate: side.wins as f64 / battles,
+            units: side
+                .names
+                .into_iter()
+                .zip(side.survived)
+                .map(|(name, survived)| UnitReport {
+                    name,
+                    survival: survived as f64 / battles,
+                })
+                .collect(),
+        }
+    }
+}
+
+fn main() {
+    let args = Args::parse();
+    parse_effects(Some(&args.effects));
+    parse_bonuses(Some(&args.bonuses));
+    let units = match parse_units(Some(&args.units)) {
+        Ok((units, _)) => units,
+        Err(err) => {
+            eprintln!("{err}");
+            exit(1);
+        }
+    };
+    parse_items(Some(&args.items), &"Rus".into());
+    let specs = match (parse_spec(&args.army1), parse_spec(&args.army2)) {
+        (Ok(spec1), Ok(spec2)) => [spec1, spec2],
+        (Err(err), _) | (_, Err(err)) => {
+            eprintln!("{err}");
+            exit(1);
+        }
+    };
+
+    let mut sides = [SideReport::default(), SideReport::default()];
+    let mut turns = 0;
+    let mut draws = 0;
+    let rules = BattleRules {
+        max_rounds: args.rounds,
+        timeout: args.timeout,
+    };
+    for battle_num in 0..args.battles {
+        let mut armys = Vec::new();
+        for (army_num, spec) in specs.iter().enumerate() {
+            match build_army(spec, &units, army_num) {
+                Ok(army) => armys.push(army),
+                Err(err) => {
+                    eprintln!("{err}");
+                    exit(1);
+                }
+            }
+        }
+        // Troops are removed from the army when they die, so survival is checked on these handles
+        let troops: Vec<Vec<TroopType>> = armys.iter().map(|army| army.troops.clone()).collect();
+        if battle_num == 0 {
+            for (side, troops) in sides.iter_mut().zip(&troops) {
+                side.names = troops
+                    .iter()
+                    .map(|troop| troop.get().unit.info.name.clone())
+                    .collect();
+                side.survived = vec![0; troops.len()];
+            }
+        }
+
+        let mut battle =
+            BattleInfo::with_rules(&mut armys, 0, 1, GameRng::new(args.seed + battle_num), rules);
+        let mut steps = 0;
+        while !battle.is_over() {
+            if steps == MAX_STEPS {
+                eprintln!("Battle {} stalled in round {}", battle_num + 1, battle.move_count);
+                exit(1);
+            }
+            steps += 1;
+            match args.policy {
+                Policy::Random => random_turn(&mut battle, &mut armys),
+                Policy::Ai => {
+                    play_pc_turns(&mut battle, &mut armys);
+                }
+            }
+            move_thing(&mut battle, &mut armys);
+        }
+        turns += battle.move_count;
+        match battle.winner {
+            Some(winner) => sides[winner].wins += 1,
+            None => draws += 1,
+        }
+        for (side, troops) in sides.iter_mut().zip(&troops) {
+            for (survived, troop) in side.survived.iter_mut().zip(troops) {
+                if !troop.get().is_dead() {
+                    *survived += 1;
+                }
+            }
+        }
+    }
+
+    let battles = args.battles.max(1) as f64;
+    let [army1, army2] = sides.map(|side| ArmyReport::new(side, battles));
+    let report = Report {
+        battles: args.battles,
+        draws,
+        average_turns: turns as f64 / battles,
+        army1,
+        army2,
+    };
+    if args.json {
+        if let Err(err) = serde_json::to_writer_pretty(stdout(), &report) {
+            eprintln!("{err}");
+            exit(1);
+        }
+        println!();
+    } else {
+        println!("Battles: {}", report.battles);
+        println!("Draws: {}", report.draws);
+        println!("Average turns: {:.2}", report.average_turns);
+        for (num, army) in [&report.army1, &report.army2].into_iter().enumerate() {
+            println!(
+                "Army {}: {} wins ({:.1}%)",
+                num + 1,
+                army.wins,
+                army.win_rate * 100.
+            );
+            for unit in &army.units {
+                println!("    {}: survived {:.1}%", unit.name, unit.survival * 100.);
+            }
+        }
+    }
+}
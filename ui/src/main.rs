@@ -4,7 +4,12 @@
 
 use alkahest::{serialize, serialized_size};
 use dt_lib::{
-    battle::{army::*, battlefield::*, troop::Troop},
+    battle::{
+        ai::{auto_resolve, play_pc_turns},
+        army::*,
+        battlefield::*,
+        troop::{Troop, TroopBattleStats},
+    },
     items::item::*,
     locale::{parse_locale, Locale},
     map::{
@@ -14,7 +19,11 @@ use dt_lib::{
         tile::*,
     },
     network::net::*,
-    parse::{parse_items, parse_objects, parse_settings, parse_story, parse_units},
+    parse::{
+        parse_bonuses, parse_effects, parse_items, parse_objects, parse_settings, parse_spells,
+        parse_story, parse_terrain, parse_units,
+    },
+    rng::GameRng,
     time::time::Data as TimeData,
     units::unit::{ActionResult, Unit, UnitPos},
 };
@@ -25,7 +34,7 @@ use notan_ui::{
 use num::clamp;
 use once_cell::sync::Lazy;
 use parking_lot::MappedRwLockReadGuard;
-use rand::{prelude::ThreadRng, thread_rng, Rng};
+use rand::Rng;
 use std::{
     array::from_fn,
     collections::{HashMap, VecDeque},
@@ -213,14 +222,16 @@ fn move_thing(battle: &mut BattleInfo, armys: &mut Vec<Army>) {
     check_win(battle, armys);
     check_row_fall(battle, armys);
     battle.remove_corpses(armys);
-    if battle.winner.is_none() {
-        if battle.active_unit == None {
-            next_move(battle, armys);
-            battle.active_unit = battle.search_next_active(armys);
-        }
+    battle.update_initiative(armys);
+    if !battle.is_over() && battle.initiative.is_empty() {
+        next_move(battle, armys);
+    }
+    battle.active_unit = battle.search_next_active();
+    if !battle.is_over() {
         battle.can_interact = search_interactions(battle, armys);
     } else {
         //state.menu_id = Menu::Start as usize;
+        battle.initiative.clear();
     }
 }
 
@@ -525,7 +536,7 @@ fn gen_forms(size: (f32, f32)) -> Result<(), String> {
 											if let Some(troop) = troop {
 												let icon_index = {
 													let unit = &troop.get().unit;
-													let text = unit.to_string();
+													let text = format!("{}\n{}", unit, unit.stat_breakdown_text());
 													container.inside.as_mut().unwrap().text = text;
 													unit.info.icon_index as i64
 												};
@@ -576,7 +587,12 @@ fn gen_forms(size: (f32, f32)) -> Result<(), String> {
                                             return;
                                         }
                                         if let Some(item_index) = get_menu_value_num(state, "items_item_index") {
-                                            unit.add_item(Item { index: item_index as usize }.into(), slot);
+                                            let item = Item { index: item_index as usize };
+                                            if item.is_potion() {
+                                                item.drink(unit);
+                                            } else {
+                                                unit.add_item(item, slot);
+                                            }
                                             set_menu_value_num(state, "items_unit_stat_changed", 1);
                                         }
                                     }
@@ -644,7 +660,7 @@ fn gen_forms(size: (f32, f32)) -> Result<(), String> {
                              _,
                              _,
                              state: &mut State| {
-                                let tilemap = gen_tilemap();
+                                let tilemap = gen_tilemap(&mut state.gamemap.rng);
                                 let decomap = gen_decomap(
                                     tilemap.1,
                                     state
@@ -652,6 +668,7 @@ fn gen_forms(size: (f32, f32)) -> Result<(), String> {
                                         .iter()
                                         .position(|obj| obj.path == "Tree0.png")
                                         .unwrap(),
+                                    &mut state.gamemap.rng,
                                 );
                                 state.gamemap.tilemap = tilemap.0;
                                 state.gamemap.decomap = decomap;
@@ -851,7 +868,7 @@ fn gen_forms(size: (f32, f32)) -> Result<(), String> {
 										let diff = (pos.0 as i64 - goal.0 as i64, pos.1 as i64 - goal.1 as i64);
 										if -1 <= diff.0 && diff.0 <= 1 && -1 <= diff.1 && diff.1 <= 1 {
 											if state.battle.is_none() {
-												let battle = BattleInfo::new(&mut state.gamemap.armys, army, 0);
+												let battle = state.gamemap.start_battle(army, 0);
 												state.battle = Some(battle);
 											}
 											state.menu_id = Menu::Battle as usize;
@@ -891,7 +908,7 @@ fn gen_forms(size: (f32, f32)) -> Result<(), String> {
 									} else { army.building = None; }
 									state.gamemap.recalc_armies_hitboxes();
 								}
-								state.gamemap.time.minutes += 10;
+								state.gamemap.pass_time();
 
 								for i in 0..state.gameevents.len() {
 									if let Some(executions) = execute_event(i, &mut state.gamemap, &mut state.gameevents, &state.units, false) {
@@ -903,7 +920,7 @@ fn gen_forms(size: (f32, f32)) -> Result<(), String> {
 												},
 												Execute::StartBattle(army, _) => {
 													if state.battle.is_none() {
-														let battle = BattleInfo::new(&mut state.gamemap.armys, army, 0);
+														let battle = state.gamemap.start_battle(army, 0);
 														state.battle = Some(battle);
 													}
 													set_menu_value_num(state, "start_menu", 1);
@@ -1565,6 +1582,30 @@ fn gen_forms(size: (f32, f32)) -> Result<(), String> {
                 }
             }
         }
+        // Turn order of the current round
+        for (i, &(army, index)) in battle.initiative.iter().enumerate() {
+            let Some(troop) = state.gamemap.armys[army].troops.get(index) else {
+                continue;
+            };
+            let pos = Position(drawing_pos.0 + i as f32 * 50., drawing_pos.1 + 480.);
+            let texture = state
+                .assets
+                .get("assets/Icons")
+                .unwrap()
+                .get(&*format!("unit_{}.png", troop.get().unit.info.icon_index))
+                .unwrap()
+                .lock()
+                .unwrap();
+            draw.image(&texture).position(pos.0, pos.1).size(46., 46.);
+            draw.rect(pos.into(), (46., 46.))
+                .color(Color::TRANSPARENT)
+                .stroke_color(if army == battle.army1 {
+                    Color::BLUE
+                } else {
+                    Color::RED
+                })
+                .stroke(if i == 0 { 4. } else { 2. });
+        }
     }
     #[repr(u64)]
     enum StartSubMenu {
@@ -1685,11 +1726,12 @@ fn gen_forms(size: (f32, f32)) -> Result<(), String> {
                                             .unwrap_or(1);
                                         match &mut container.inside {
                                             Some::<Text<State, String>>(text) => {
-                                                text.text = state
-                                                    .units
-                                                    .get(num as usize)
-                                                    .unwrap()
-                                                    .to_string();
+                                                let unit = state.units.get(num as usize).unwrap();
+                                                text.text = format!(
+                                                    "{}\n{}",
+                                                    unit,
+                                                    unit.stat_breakdown_text()
+                                                );
                                                 set_menu_value_num(state, "char_view_changed", 0);
                                             }
                                             None => {}
@@ -1868,7 +1910,10 @@ fn gen_forms(size: (f32, f32)) -> Result<(), String> {
         to: (usize, usize),
     ) -> AnimationTime<AssetsMap> {
         let texture = match res {
-            ActionResult::Move => {
+            ActionResult::Move
+            | ActionResult::Defend
+            | ActionResult::Wait
+            | ActionResult::Surrender => {
                 |assets: &AssetsMap| assets.get_texture("assets/Window", "buff.png").clone()
             }
             ActionResult::Buff => {
@@ -1954,8 +1999,8 @@ fn gen_forms(size: (f32, f32)) -> Result<(), String> {
                                             let index = (button.rect.pos.0 / BETWEEN_CELLS) as usize;
 											if !state.animations.is_empty() {return;}
 											let Some(battle) = &mut state.battle else { return; };
-											if let Some(_) = battle.winner { state.menu_id = Menu::Start as usize; return; }
-                                            let res = handle_action(Action::Cell(index, 0), battle, &mut state.gamemap.armys);
+											if battle.is_over() { state.gamemap.finish_battle(battle); state.menu_id = Menu::Start as usize; return; }
+                                            let (res, _) = handle_action(Action::Cell(index, 0), battle, &mut state.gamemap.armys);
 											handle_animations(state, (index, 0), res);
                                         }).build().unwrap()
                                     }).collect::<Vec<_>>())
@@ -1975,8 +2020,8 @@ fn gen_forms(size: (f32, f32)) -> Result<(), String> {
                                     let index = (button.rect.pos.0 / BETWEEN_CELLS) as usize + *MAX_TROOPS / 2;
 									if !state.animations.is_empty() {return;}
 									let Some(battle) = &mut state.battle else { return; };
-									if let Some(_) = battle.winner { state.menu_id = Menu::Start as usize; return; }
-                                    let res = handle_action(Action::Cell(index, 0), battle, &mut state.gamemap.armys);
+									if battle.is_over() { state.gamemap.finish_battle(battle); state.menu_id = Menu::Start as usize; return; }
+                                    let (res, _) = handle_action(Action::Cell(index, 0), battle, &mut state.gamemap.armys);
 									handle_animations(state, (index, 0), res);
                                 })
                                     .build().unwrap()
@@ -2006,8 +2051,8 @@ fn gen_forms(size: (f32, f32)) -> Result<(), String> {
                                     let index = (button.rect.pos.0 / BETWEEN_CELLS) as usize + *MAX_TROOPS / 2;
 									if !state.animations.is_empty() {return;}
 									let Some(battle) = &mut state.battle else { return; };
-									if let Some(_) = battle.winner { state.menu_id = Menu::Start as usize; return; }
-                                    let res = handle_action(Action::Cell(index, 1), battle, &mut state.gamemap.armys);
+									if battle.is_over() { state.gamemap.finish_battle(battle); state.menu_id = Menu::Start as usize; return; }
+                                    let (res, _) = handle_action(Action::Cell(index, 1), battle, &mut state.gamemap.armys);
 									handle_animations(state, (index, 1), res);
                                 }).build().unwrap()
                             }).collect::<Vec<_>>())
@@ -2027,8 +2072,8 @@ fn gen_forms(size: (f32, f32)) -> Result<(), String> {
                                     let index = (button.rect.pos.0 / BETWEEN_CELLS) as usize;
 									if !state.animations.is_empty() {return;}
 									let Some(battle) = &mut state.battle else { return; };
-									if let Some(_) = battle.winner { state.menu_id = Menu::Start as usize; return; }
-                                    let res = handle_action(Action::Cell(index, 1), battle, &mut state.gamemap.armys);
+									if battle.is_over() { state.gamemap.finish_battle(battle); state.menu_id = Menu::Start as usize; return; }
+                                    let (res, _) = handle_action(Action::Cell(index, 1), battle, &mut state.gamemap.armys);
 									handle_animations(state, (index, 1), res);
                                 })
                                     .build().unwrap()
@@ -2098,11 +2143,22 @@ fn gen_forms(size: (f32, f32)) -> Result<(), String> {
         }),
         after_draw: Some(|_container, app, _assets, _plugins, state: &mut State| {
             if app.keyboard.is_down(KeyCode::Escape) { state.menu_id = Menu::Main as usize; }
+			if state.animations.is_empty() && let Some(battle) = &mut state.battle {
+				play_pc_turns(battle, &mut state.gamemap.armys);
+			}
             if app.keyboard.was_pressed(KeyCode::Space) {
 				let Some(battle) = &mut state.battle else { return; };
-				if let Some(active_unit) = battle.active_unit {
-					handle_action(Action::Cell(active_unit.1, active_unit.0), battle, &mut state.gamemap.armys);
-				}
+				handle_action(Action::Defend, battle, &mut state.gamemap.armys);
+            }
+            if app.keyboard.was_pressed(KeyCode::W) {
+				let Some(battle) = &mut state.battle else { return; };
+				handle_action(Action::Wait, battle, &mut state.gamemap.armys);
+            }
+            if app.keyboard.was_pressed(KeyCode::A) {
+				let Some(battle) = &mut state.battle else { return; };
+				auto_resolve(battle, &mut state.gamemap.armys);
+				state.gamemap.finish_battle(battle);
+				state.menu_id = Menu::Start as usize;
             }
         }),
         pos: Position(0., 0.)
@@ -2128,7 +2184,7 @@ fn gen_forms(size: (f32, f32)) -> Result<(), String> {
                                             Rect { pos: Position(0., 0.), size: Size(92., 92.) }
                                         ).if_clicked(|button, _app, _assets, _plugins, state| {
                                             let index = (button.rect.pos.0 / BETWEEN_CELLS) as usize;
-											handle_server_action(&mut state.connection, (index, 0));
+											handle_server_action(&mut state.connection, Action::Cell(index, 0));
                                         }).build().unwrap()
                                     }).collect::<Vec<_>>())
                                 .interval(Position(10., 0.))
@@ -2147,7 +2203,7 @@ fn gen_forms(size: (f32, f32)) -> Result<(), String> {
                                     Rect { pos: Position(0., 0.), size: Size(92., 92.) }
                                 ).if_clicked(|button, _app, _assets, _plugins, state| {
                                     let index = (button.rect.pos.0 / BETWEEN_CELLS) as usize + *MAX_TROOPS / 2;
-									handle_server_action(&mut state.connection, (index, 0));
+									handle_server_action(&mut state.connection, Action::Cell(index, 0));
                                 })
                                     .build().unwrap()
                             }).collect::<Vec<_>>())
@@ -2176,7 +2232,7 @@ fn gen_forms(size: (f32, f32)) -> Result<(), String> {
                                     Rect { pos: Position(0., 0.), size: Size(92., 92.) }
                                 ).if_clicked(|button, _app, _assets, _plugins, state| {
                                     let index = (button.rect.pos.0 / BETWEEN_CELLS) as usize + *MAX_TROOPS / 2;
-									handle_server_action(&mut state.connection, (index, 1));
+									handle_server_action(&mut state.connection, Action::Cell(index, 1));
                                 }).build().unwrap()
                             }).collect::<Vec<_>>())
                                 .interval(Position(10., 0.))
@@ -2195,7 +2251,7 @@ fn gen_forms(size: (f32, f32)) -> Result<(), String> {
                                     Rect { pos: Position(0., 0.), size: Size(92., 92.) }
                                 ).if_clicked(|button, _app, _assets, _plugins, state| {
                                     let index = (button.rect.pos.0 / BETWEEN_CELLS) as usize;
-									handle_server_action(&mut state.connection, (index, 1));
+									handle_server_action(&mut state.connection, Action::Cell(index, 1));
                                 })
                                     .build().unwrap()
                             }).collect::<Vec<_>>())
@@ -2305,9 +2361,11 @@ fn gen_forms(size: (f32, f32)) -> Result<(), String> {
             if app.keyboard.is_down(KeyCode::Escape) { state.menu_id = Menu::Main as usize; }
             if app.keyboard.was_pressed(KeyCode::Space) {
 				let Some(battle) = &mut state.battle else { return; };
-				if let Some(active_unit) = battle.active_unit {
-					handle_action(Action::Cell(active_unit.1, active_unit.0), battle, &mut state.gamemap.armys);
-				}
+				handle_action(Action::Defend, battle, &mut state.gamemap.armys);
+            }
+            if app.keyboard.was_pressed(KeyCode::W) {
+				let Some(battle) = &mut state.battle else { return; };
+				handle_action(Action::Wait, battle, &mut state.gamemap.armys);
             }
         }),
         pos: Position(0., 0.)
@@ -2317,7 +2375,7 @@ fn gen_forms(size: (f32, f32)) -> Result<(), String> {
 }
 
 type Tilemap<T> = [[T; MAP_SIZE]; MAP_SIZE];
-fn gen_army_troops(rng: &mut ThreadRng, units: &Vec<Unit>, army: usize) -> Vec<TroopType> {
+fn gen_army_troops(rng: &mut GameRng, units: &Vec<Unit>, army: usize) -> Vec<TroopType> {
     let mut troops = (0..*MAX_TROOPS / 2)
         .map(|i| {
             Troop {
@@ -2326,6 +2384,7 @@ fn gen_army_troops(rng: &mut ThreadRng, units: &Vec<Unit>, army: usize) -> Vec<T
                 is_main: false,
                 pos: UnitPos::from_index(i),
                 custom_name: None,
+                battle_stats: TroopBattleStats::default(),
                 unit: {
                     let mut unit = loop {
                         let unit = units[rng.gen_range(1..100)].clone();
@@ -2349,6 +2408,7 @@ fn gen_army_troops(rng: &mut ThreadRng, units: &Vec<Unit>, army: usize) -> Vec<T
                     is_main: false,
                     pos: UnitPos::from_index(i),
                     custom_name: None,
+                    battle_stats: TroopBattleStats::default(),
                     unit: {
                         let mut unit = loop {
                             let unit = units[rng.gen_range(1..100)].clone();
@@ -2366,9 +2426,8 @@ fn gen_army_troops(rng: &mut ThreadRng, units: &Vec<Unit>, army: usize) -> Vec<T
     );
     troops
 }
-fn gen_tilemap() -> (Tilemap<usize>, (u32, u32)) {
+fn gen_tilemap(rng: &mut GameRng) -> (Tilemap<usize>, (u32, u32)) {
     let noise = PerlinNoise::new();
-    let mut rng = thread_rng();
     let seeds = (rng.gen_range(0..10000), rng.gen_range(0..10000));
     let nm1 = NoiseMap::new(noise)
         .set(Seed::of(seeds.0))
@@ -2403,9 +2462,8 @@ fn gen_tilemap() -> (Tilemap<usize>, (u32, u32)) {
         seeds,
     )
 }
-fn gen_decomap(seeds: (u32, u32), first_tree_index: usize) -> Tilemap<Option<usize>> {
+fn gen_decomap(seeds: (u32, u32), first_tree_index: usize, rng: &mut GameRng) -> Tilemap<Option<usize>> {
     let noise = PerlinNoise::new();
-    let mut rng = thread_rng();
     let nm1 = NoiseMap::new(noise)
         .set(Seed::of(seeds.0))
         .set(Step::of(0.005, 0.005));
@@ -2559,7 +2617,11 @@ fn setup(app: &mut App, app_assets: &mut Assets, gfx: &mut Graphics) -> State {
     app.window().set_fullscreen(settings.fullscreen);
     app.window()
         .set_size(settings.init_size.0, settings.init_size.1);
+    parse_effects(None);
+    parse_bonuses(None);
+    parse_spells(None);
     let req_assets = parse_items(None, &settings.locale);
+    parse_terrain(None);
     load_assets(gfx, &mut assets, req_assets.1, req_assets.0).expect("Loading items assets failed");
     {
         let locale = &mut LOCALE.lock().unwrap();